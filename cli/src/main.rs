@@ -1,13 +1,18 @@
 use colored_json::prelude::*;
 use eyre::{eyre, Context};
 use futures::{SinkExt, StreamExt};
+use shrubbery_client::Client;
+use shrubbery_common::audit::ChainVerifier;
 use shrubbery_common::frame::{Frame, FrameType};
 use shrubbery_common::framed::FramedConnection;
+use shrubbery_common::DocId;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::{fs, select};
-use tracing::{debug, error, info, trace};
+use tracing::{error, info, trace};
 
 #[derive(StructOpt)]
 struct Opts {
@@ -25,6 +30,12 @@ struct Opts {
     )]
     token: String,
 
+    #[structopt(
+        long,
+        help = "Connect to the server's local admin socket at this path instead. No token is needed."
+    )]
+    admin_socket: Option<PathBuf>,
+
     #[structopt(subcommand)]
     cmd: Cmd,
 }
@@ -51,6 +62,33 @@ enum Cmd {
     RevokeTokensForUser {
         user: String,
     },
+    /// Print the server's version, features and limits
+    ServerInfo,
+    /// List the authenticated connections
+    ListConnections,
+    /// List the docs with clients connected and how many have each open
+    ListOpenDocs,
+    /// Disconnect a connection without letting it resume
+    KickConnection {
+        /// The connection's id, as printed by list-connections
+        connection: u64,
+    },
+    /// Close a doc a connection has open
+    KickFromDoc {
+        /// The connection's id, as printed by list-connections
+        connection: u64,
+        doc: DocId,
+    },
+    /// Print the audit log as newline-delimited JSON, checking the hash chain as it goes
+    ExportAuditLog {
+        #[structopt(long)]
+        /// Start after the entry with this number, such as the last one previously exported
+        after: Option<u64>,
+        #[structopt(long)]
+        /// Fail unless the first entry follows the entry with this hash, such as the hash
+        /// printed by the previous export
+        after_hash: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -71,6 +109,8 @@ async fn main() -> eyre::Result<()> {
         }
     );
 
+    let skip_auth = skip_auth || opts.admin_socket.is_some();
+
     let token = if skip_auth {
         "".to_string()
     } else if opts.token == "-" {
@@ -85,14 +125,33 @@ async fn main() -> eyre::Result<()> {
         contents.trim().to_string()
     };
 
-    let socket = TcpStream::connect((opts.host.as_str(), opts.port)).await?;
-    let mut socket = FramedConnection::establish_shrub(socket).await?;
+    let mut socket = if let Some(path) = &opts.admin_socket {
+        let socket = connect_admin_socket(path).await?;
+        info!("Connected to {}", path.display());
+        socket
+    } else {
+        let socket = TcpStream::connect((opts.host.as_str(), opts.port)).await?;
+        let socket = FramedConnection::establish_shrub(socket).await?;
+        info!("Connected to {}:{}", opts.host, opts.port);
+        socket
+    };
 
-    if !skip_auth {
-        authenticate(&mut socket, token).await?;
+    if let Cmd::Raw { frame, .. } = opts.cmd {
+        if !skip_auth {
+            socket
+                .send(Frame::new(-1, FrameType::Authenticate { token }))
+                .await?;
+        }
+        return raw(socket, frame).await;
     }
 
-    info!("Connected to {}:{}", opts.host, opts.port);
+    let client = Client::new(socket);
+    if !skip_auth {
+        client
+            .authenticate(token)
+            .await
+            .wrap_err("error authenticating")?;
+    }
 
     match opts.cmd {
         Cmd::MintToken {
@@ -101,101 +160,150 @@ async fn main() -> eyre::Result<()> {
             lifetime,
         } => {
             let info = info.map(|s| serde_json::from_str(s.as_str())).transpose()?;
-            let frame = Frame::new(
-                -2,
-                FrameType::MintToken {
-                    user,
-                    info,
-                    lifetime_seconds: lifetime,
-                },
-            );
-            socket.send(frame).await?;
-            let reply = read_reply(&mut socket, -2).await?;
-            match reply.frame {
-                FrameType::MintTokenResponse { token } => {
-                    println!("{}", token);
-                    Ok(())
-                }
-                FrameType::Error { error } => Err(eyre!("error minting token: {}", error)),
-                _ => Err(eyre!("unexpected reply frame: {:?}", reply)),
-            }
+            let token = client
+                .mint_token(user, info, Duration::from_secs(lifetime))
+                .await
+                .wrap_err("error minting token")?;
+            println!("{}", token);
+            Ok(())
         }
         Cmd::RevokeTokensForUser { user } => {
-            let frame = Frame::new(-2, FrameType::RevokeTokensForUser { user });
-            socket.send(frame).await?;
-            let reply = read_reply(&mut socket, -2).await?;
-            match reply.frame {
-                FrameType::Ok => {
-                    println!("ok");
-                    Ok(())
-                }
-                FrameType::Error { error } => Err(eyre!("error revoking tokens: {}", error)),
-                _ => Err(eyre!("unexpected reply frame: {:?}", reply)),
-            }
+            client
+                .revoke_tokens_for_user(user)
+                .await
+                .wrap_err("error revoking tokens")?;
+            println!("ok");
+            Ok(())
         }
-        Cmd::Raw {
-            frame,
-            skip_auth: _,
-        } => {
-            let frame = serde_json::from_str(&frame).wrap_err("input is not a valid frame")?;
-            socket.send(frame).await?;
-            trace!("sent frame");
-
-            let mut reader = BufReader::new(tokio::io::stdin()).lines();
+        Cmd::ServerInfo => {
+            let info = client
+                .server_info()
+                .await
+                .wrap_err("error getting server info")?;
+            let json = serde_json::to_string_pretty(&info)?;
+            println!("{}", json.to_colored_json_auto()?);
+            Ok(())
+        }
+        Cmd::ListConnections => {
+            let connections = client
+                .list_connections()
+                .await
+                .wrap_err("error listing connections")?;
+            let json = serde_json::to_string_pretty(&connections)?;
+            println!("{}", json.to_colored_json_auto()?);
+            Ok(())
+        }
+        Cmd::ListOpenDocs => {
+            let docs = client
+                .list_open_docs()
+                .await
+                .wrap_err("error listing open docs")?;
+            let json = serde_json::to_string_pretty(&docs)?;
+            println!("{}", json.to_colored_json_auto()?);
+            Ok(())
+        }
+        Cmd::KickConnection { connection } => {
+            client
+                .kick_connection(connection)
+                .await
+                .wrap_err("error kicking connection")?;
+            println!("ok");
+            Ok(())
+        }
+        Cmd::KickFromDoc { connection, doc } => {
+            client
+                .kick_from_doc(connection, doc)
+                .await
+                .wrap_err("error kicking connection from doc")?;
+            println!("ok");
+            Ok(())
+        }
+        Cmd::ExportAuditLog { after, after_hash } => {
+            let mut verifier = ChainVerifier::new();
+            let mut after = after;
+            let mut first = true;
             loop {
-                select! {
-                    frame = socket.next() => {
-                        let Some(frame) = frame else {
-                            info!("Connection closed");
-                            return Ok(());
-                        };
-                        let frame = frame?;
-                        let json = serde_json::to_string_pretty(&frame)?;
-                        print!("\n{}\n\n", json.to_colored_json_auto()?);
-                    }
-
-                    line = reader.next_line() => {
-                        let Some(line) = line? else {
-                            return Ok(())
-                        };
-                        let frame = match serde_json::from_str(&line) {
-                            Ok(frame) => frame,
-                            Err(err) => {
-                                error!("invalid frame: {}", err);
-                                continue;
-                            },
-                        };
-                        socket.send(frame).await?;
-                        trace!("sent frame");
+                let entries = client
+                    .query_audit_log(after, None)
+                    .await
+                    .wrap_err("error querying audit log")?;
+                let Some(last) = entries.last() else {
+                    break;
+                };
+                after = Some(last.seq);
+                for entry in &entries {
+                    if first {
+                        first = false;
+                        if let Some(hash) = &after_hash {
+                            if &entry.prev_hash != hash {
+                                return Err(eyre!(
+                                    "audit entry {} doesn't follow the entry with hash {}",
+                                    entry.seq,
+                                    hash
+                                ));
+                            }
+                        }
                     }
+                    verifier.push(entry)?;
+                    println!("{}", serde_json::to_string(entry)?);
                 }
             }
+            match verifier.last_hash() {
+                Some(hash) => eprintln!("Chain verified up to hash {}", hash),
+                None => eprintln!("No entries"),
+            }
+            Ok(())
         }
+        Cmd::Raw { .. } => unreachable!("handled above"),
     }
 }
 
-async fn authenticate(socket: &mut FramedConnection, token: String) -> eyre::Result<()> {
-    socket
-        .send(Frame::new(-1, FrameType::Authenticate { token }))
-        .await?;
-    let reply = read_reply(socket, -1).await?;
-    match reply.frame {
-        FrameType::Ok => return Ok(()),
-        FrameType::Error { error: msg } => return Err(eyre!("error authenticating: {}", msg)),
-        _ => return Err(eyre!("unexpected reply frame: {:?}", reply)),
-    }
-}
+/// Sends `frame` and then each frame read from stdin, printing every frame received.
+async fn raw(mut socket: FramedConnection, frame: String) -> eyre::Result<()> {
+    let frame = serde_json::from_str(&frame).wrap_err("input is not a valid frame")?;
+    socket.send(frame).await?;
+    trace!("sent frame");
 
-async fn read_reply(socket: &mut FramedConnection, id: i32) -> eyre::Result<Frame> {
+    let mut reader = BufReader::new(tokio::io::stdin()).lines();
     loop {
-        let Some(frame) = socket.next().await else {
-            return Err(eyre!("unexpected close"));
-        };
-        let frame = frame?;
-        if frame.reply_to != Some(id) {
-            debug!("ignoring {:?} (expected reply to {}", frame, id);
-            continue;
+        select! {
+            frame = socket.next() => {
+                let Some(frame) = frame else {
+                    info!("Connection closed");
+                    return Ok(());
+                };
+                let frame = frame?;
+                let json = serde_json::to_string_pretty(&frame)?;
+                print!("\n{}\n\n", json.to_colored_json_auto()?);
+            }
+
+            line = reader.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(())
+                };
+                let frame = match serde_json::from_str(&line) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        error!("invalid frame: {}", err);
+                        continue;
+                    },
+                };
+                socket.send(frame).await?;
+                trace!("sent frame");
+            }
         }
-        return Ok(frame);
     }
 }
+
+#[cfg(unix)]
+async fn connect_admin_socket(path: &Path) -> eyre::Result<FramedConnection> {
+    let socket = tokio::net::UnixStream::connect(path)
+        .await
+        .wrap_err_with(|| format!("failed to connect to {}", path.display()))?;
+    FramedConnection::establish_shrub_unix(socket).await
+}
+
+#[cfg(not(unix))]
+async fn connect_admin_socket(_path: &Path) -> eyre::Result<FramedConnection> {
+    Err(eyre!("--admin-socket is only supported on unix"))
+}
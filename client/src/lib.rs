@@ -0,0 +1,573 @@
+//! An async client for the shrubbery protocol.
+//!
+//! A [`Client`] owns a connection in a background task, numbers requests with negative ids and
+//! routes each reply back to the request it answers. Presence for open docs is delivered to the
+//! [`DocHandle`] returned by [`Client::open`].
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use shrubbery_common::frame::{
+    AuditEntry, ConnectionInfo, DocInfo, ErrorCode, Frame, FrameType, OpenDocInfo, PresenceFrame,
+    ServerInfo,
+};
+use shrubbery_common::framed::FramedConnection;
+use shrubbery_common::DocId;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, trace};
+
+/// How many presence updates are buffered per open doc before further updates are dropped.
+const PRESENCE_BUFFER: usize = 12;
+
+/// Anything frames can be sent and received over, such as a [`FramedConnection`].
+pub trait Transport:
+    Stream<Item = io::Result<Frame>> + Sink<Frame, Error = io::Error> + Send + Unpin + 'static
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = io::Result<Frame>> + Sink<Frame, Error = io::Error> + Send + Unpin + 'static
+{
+}
+
+/// A connection to a shrubbery server. Cloning gives another handle to the same connection,
+/// which closes once every handle has been dropped.
+#[derive(Clone, Debug)]
+pub struct Client {
+    commands: mpsc::Sender<Command>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Send {
+        frame: FrameType,
+        reply_tx: Option<oneshot::Sender<Frame>>,
+    },
+    /// Sends `Open`. Presence for the doc goes to `presence_tx` from then on, and it replaces the
+    /// doc's earlier subscriber once the server accepts.
+    Open {
+        doc: DocId,
+        presence_tx: mpsc::Sender<PresenceFrame>,
+        reply_tx: oneshot::Sender<Frame>,
+    },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The server replied with an error frame.
+    Server {
+        code: ErrorCode,
+        message: String,
+        details: Option<serde_json::Value>,
+    },
+    /// The server replied with a frame that doesn't answer the request.
+    UnexpectedReply(Box<FrameType>),
+    /// The connection closed before the server replied.
+    Closed,
+}
+
+impl Client {
+    /// Connects to the shrubbery protocol port over TCP without authenticating.
+    pub async fn connect(addr: impl ToSocketAddrs) -> eyre::Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        let socket = FramedConnection::establish_shrub(socket).await?;
+        Ok(Self::new(socket))
+    }
+
+    /// Starts a client over an established transport. Call [`Client::authenticate`] before
+    /// anything else unless the transport is already authenticated, as with the admin socket.
+    pub fn new<T: Transport>(transport: T) -> Self {
+        let (commands, commands_rx) = mpsc::channel(16);
+        tokio::spawn(run(transport, commands_rx));
+        Self { commands }
+    }
+
+    pub async fn authenticate(&self, token: String) -> Result<(), Error> {
+        match self.request(FrameType::Authenticate { token }).await? {
+            FrameType::Ok => Ok(()),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn server_info(&self) -> Result<ServerInfo, Error> {
+        match self.request(FrameType::GetServerInfo).await? {
+            FrameType::ServerInfo { info } => Ok(info),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn mint_token(
+        &self,
+        user: String,
+        info: Option<serde_json::Value>,
+        lifetime: Duration,
+    ) -> Result<String, Error> {
+        let frame = FrameType::MintToken {
+            user,
+            info,
+            lifetime_seconds: lifetime.as_secs(),
+        };
+        match self.request(frame).await? {
+            FrameType::MintTokenResponse { token } => Ok(token),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn revoke_tokens_for_user(&self, user: String) -> Result<(), Error> {
+        match self
+            .request(FrameType::RevokeTokensForUser { user })
+            .await?
+        {
+            FrameType::Ok => Ok(()),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn create_doc(&self) -> Result<DocInfo, Error> {
+        match self.request(FrameType::CreateDoc).await? {
+            FrameType::DocInfo { info } => Ok(info),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    pub async fn get_doc(&self, doc: DocId) -> Result<DocInfo, Error> {
+        match self.request(FrameType::GetDoc { doc }).await? {
+            FrameType::DocInfo { info } => Ok(info),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Lists the server's authenticated connections. Root only.
+    pub async fn list_connections(&self) -> Result<Vec<ConnectionInfo>, Error> {
+        match self.request(FrameType::ListConnections).await? {
+            FrameType::ConnectionList { connections } => Ok(connections),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Lists the docs with clients connected. Root only.
+    pub async fn list_open_docs(&self) -> Result<Vec<OpenDocInfo>, Error> {
+        match self.request(FrameType::ListOpenDocs).await? {
+            FrameType::OpenDocList { docs } => Ok(docs),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Closes a connection, as listed by [`Client::list_connections`]. Root only.
+    pub async fn kick_connection(&self, connection: u64) -> Result<(), Error> {
+        match self
+            .request(FrameType::KickConnection { connection })
+            .await?
+        {
+            FrameType::Ok => Ok(()),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Closes a doc a connection has open. Root only.
+    pub async fn kick_from_doc(&self, connection: u64, doc: DocId) -> Result<(), Error> {
+        match self
+            .request(FrameType::KickFromDoc { connection, doc })
+            .await?
+        {
+            FrameType::Ok => Ok(()),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Lists audit log entries after the entry numbered `after`. An empty list means there are
+    /// no more. Root only.
+    pub async fn query_audit_log(
+        &self,
+        after: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        match self
+            .request(FrameType::QueryAuditLog { after, limit })
+            .await?
+        {
+            FrameType::AuditLog { entries } => Ok(entries),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Opens a doc, returning a handle that streams the presence of everyone else in it.
+    ///
+    /// Opening the same doc again replaces the earlier handle, whose stream then ends. If opening
+    /// it again fails, the earlier handle is kept.
+    pub async fn open(&self, doc: DocId) -> Result<DocHandle, Error> {
+        let (presence_tx, presence_rx) = mpsc::channel(PRESENCE_BUFFER);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.commands
+            .send(Command::Open {
+                doc,
+                presence_tx,
+                reply_tx,
+            })
+            .await
+            .map_err(|_| Error::Closed)?;
+        let reply = reply_rx.await.map_err(|_| Error::Closed)?;
+        match into_result(reply)? {
+            FrameType::Ok => Ok(DocHandle {
+                doc,
+                client: self.clone(),
+                presence_rx,
+            }),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Sets this connection's presence in an open doc, returning once the server has accepted it.
+    pub async fn update_presence(
+        &self,
+        doc: DocId,
+        presence: serde_json::Value,
+    ) -> Result<(), Error> {
+        match self
+            .request(FrameType::UpdatePresence { doc, presence })
+            .await?
+        {
+            FrameType::Ok => Ok(()),
+            other => Err(Error::UnexpectedReply(Box::new(other))),
+        }
+    }
+
+    /// Sends a frame and waits for the reply to it, turning error frames into [`Error::Server`].
+    pub async fn request(&self, frame: FrameType) -> Result<FrameType, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(frame, Some(reply_tx)).await?;
+        let reply = reply_rx.await.map_err(|_| Error::Closed)?;
+        into_result(reply)
+    }
+
+    async fn send(
+        &self,
+        frame: FrameType,
+        reply_tx: Option<oneshot::Sender<Frame>>,
+    ) -> Result<(), Error> {
+        self.commands
+            .send(Command::Send { frame, reply_tx })
+            .await
+            .map_err(|_| Error::Closed)
+    }
+}
+
+/// An open doc. Streams the presence updates of other connections in the doc.
+///
+/// The protocol has no way to close a doc, so it stays open on the server until the connection
+/// closes even after the handle is dropped. The stream ends if an admin kicks the connection from
+/// the doc.
+#[derive(Debug)]
+pub struct DocHandle {
+    doc: DocId,
+    client: Client,
+    presence_rx: mpsc::Receiver<PresenceFrame>,
+}
+
+impl DocHandle {
+    pub fn doc(&self) -> DocId {
+        self.doc
+    }
+
+    pub async fn update_presence(&self, presence: serde_json::Value) -> Result<(), Error> {
+        self.client.update_presence(self.doc, presence).await
+    }
+}
+
+impl Stream for DocHandle {
+    type Item = PresenceFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.presence_rx.poll_recv(cx)
+    }
+}
+
+/// Turns error frames into [`Error::Server`].
+fn into_result(reply: Frame) -> Result<FrameType, Error> {
+    match reply.frame {
+        FrameType::Error {
+            code,
+            error,
+            details,
+        } => Err(Error::Server {
+            code,
+            message: error,
+            details,
+        }),
+        frame => Ok(frame),
+    }
+}
+
+async fn run<T: Transport>(mut transport: T, mut commands: mpsc::Receiver<Command>) {
+    let mut next_frame_id = -1;
+    let mut pending: HashMap<i32, oneshot::Sender<Frame>> = HashMap::new();
+    let mut subscribers: HashMap<DocId, mpsc::Sender<PresenceFrame>> = HashMap::new();
+    // Subscribers by the id of their `Open`, until the server replies. Presence that arrives
+    // first is queued in their channel.
+    let mut opening: HashMap<i32, (DocId, mpsc::Sender<PresenceFrame>)> = HashMap::new();
+    loop {
+        select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    trace!("all client handles dropped");
+                    let _ = transport.close().await;
+                    return;
+                };
+                let id = next_frame_id;
+                next_frame_id -= 1;
+                let (frame, reply_tx) = match command {
+                    Command::Send { frame, reply_tx } => (frame, reply_tx),
+                    Command::Open { doc, presence_tx, reply_tx } => {
+                        opening.insert(id, (doc, presence_tx));
+                        (FrameType::Open { doc }, Some(reply_tx))
+                    }
+                };
+                if let Some(reply_tx) = reply_tx {
+                    pending.insert(id, reply_tx);
+                }
+                if let Err(err) = transport.send(Frame::new(id, frame)).await {
+                    debug!("Failed to send frame: {}", err);
+                    return;
+                }
+            }
+
+            frame = transport.next() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(err)) => {
+                        debug!("Connection failed: {}", err);
+                        return;
+                    }
+                    None => {
+                        debug!("Connection closed");
+                        return;
+                    }
+                };
+                trace!("got frame: {:?}", frame);
+                if let Some(reply_tx) = frame.reply_to.and_then(|id| pending.remove(&id)) {
+                    let opened = frame.reply_to.and_then(|id| opening.remove(&id));
+                    if let (Some((doc, presence_tx)), FrameType::Ok) = (opened, &frame.frame) {
+                        subscribers.insert(doc, presence_tx);
+                    }
+                    let _ = reply_tx.send(frame);
+                    continue;
+                }
+                match frame.frame {
+                    FrameType::Presence { updates } => {
+                        for update in updates {
+                            for (doc, presence_tx) in opening.values() {
+                                if *doc == update.doc {
+                                    let _ = presence_tx.try_send(update.clone());
+                                }
+                            }
+                            let Some(presence_tx) = subscribers.get(&update.doc) else {
+                                continue;
+                            };
+                            if let Err(mpsc::error::TrySendError::Closed(_)) =
+                                presence_tx.try_send(update)
+                            {
+                                subscribers.retain(|_, tx| !tx.is_closed());
+                            }
+                        }
+                    }
+                    FrameType::Kicked { doc: Some(doc) } => {
+                        info!("Kicked from doc {}", doc);
+                        subscribers.remove(&doc);
+                    }
+                    FrameType::Kicked { doc: None } => info!("Kicked by the server"),
+                    FrameType::Shutdown { reconnect_after_seconds } => {
+                        info!(
+                            "Server is shutting down, reconnect after {}s",
+                            reconnect_after_seconds
+                        );
+                    }
+                    other => debug!("ignoring unsolicited frame: {:?}", other),
+                }
+            }
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Server { message, .. } => write!(f, "server error: {}", message),
+            Error::UnexpectedReply(frame) => write!(f, "unexpected reply frame: {:?}", frame),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The client's end of an in-memory connection, with the test playing the server.
+    struct Channel {
+        tx: mpsc::UnboundedSender<Frame>,
+        rx: mpsc::UnboundedReceiver<Frame>,
+    }
+
+    impl Stream for Channel {
+        type Item = io::Result<Frame>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_recv(cx).map(|frame| frame.map(Ok))
+        }
+    }
+
+    impl Sink<Frame> for Channel {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, frame: Frame) -> io::Result<()> {
+            self.tx.send(frame).map_err(io::Error::other)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct Server {
+        tx: mpsc::UnboundedSender<Frame>,
+        rx: mpsc::UnboundedReceiver<Frame>,
+        next_id: i32,
+    }
+
+    impl Server {
+        async fn expect_open(&mut self, doc: DocId) -> i32 {
+            let frame = self.rx.recv().await.unwrap();
+            assert!(matches!(frame.frame, FrameType::Open { doc: opened } if opened == doc));
+            frame.id
+        }
+
+        fn send(&mut self, reply_to: Option<i32>, frame: FrameType) {
+            self.next_id += 1;
+            let frame = Frame {
+                reply_to,
+                ..Frame::new(self.next_id, frame)
+            };
+            self.tx.send(frame).unwrap();
+        }
+
+        fn send_presence(&mut self, doc: DocId, client: u32) {
+            let update = PresenceFrame {
+                client,
+                doc,
+                user: "alice".to_string(),
+                info: None,
+                presence: Some(serde_json::json!({ "cursor": client })),
+            };
+            self.send(
+                None,
+                FrameType::Presence {
+                    updates: vec![update],
+                },
+            );
+        }
+    }
+
+    fn connect() -> (Client, Server) {
+        let (client_tx, server_rx) = mpsc::unbounded_channel();
+        let (server_tx, client_rx) = mpsc::unbounded_channel();
+        let client = Client::new(Channel {
+            tx: client_tx,
+            rx: client_rx,
+        });
+        let server = Server {
+            tx: server_tx,
+            rx: server_rx,
+            next_id: 0,
+        };
+        (client, server)
+    }
+
+    async fn open(
+        client: &Client,
+        server: &mut Server,
+        doc: DocId,
+        reply: FrameType,
+    ) -> Result<DocHandle, Error> {
+        let open = tokio::spawn({
+            let client = client.clone();
+            async move { client.open(doc).await }
+        });
+        let id = server.expect_open(doc).await;
+        server.send(Some(id), reply);
+        open.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn delivers_presence_sent_before_the_open_reply() {
+        let (client, mut server) = connect();
+        let doc = DocId(1);
+        let open = tokio::spawn({
+            let client = client.clone();
+            async move { client.open(doc).await }
+        });
+        let id = server.expect_open(doc).await;
+        server.send_presence(doc, 7);
+        server.send(Some(id), FrameType::Ok);
+
+        let mut handle = open.await.unwrap().unwrap();
+        assert_eq!(handle.next().await.unwrap().client, 7);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_open_handle_when_opening_again_fails() {
+        let (client, mut server) = connect();
+        let doc = DocId(1);
+        let mut handle = open(&client, &mut server, doc, FrameType::Ok)
+            .await
+            .unwrap();
+
+        let error = FrameType::Error {
+            code: ErrorCode::Forbidden,
+            error: "forbidden".to_string(),
+            details: None,
+        };
+        let err = open(&client, &mut server, doc, error).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Server {
+                code: ErrorCode::Forbidden,
+                ..
+            }
+        ));
+
+        server.send_presence(doc, 7);
+        assert_eq!(handle.next().await.unwrap().client, 7);
+    }
+
+    #[tokio::test]
+    async fn replaces_the_open_handle_when_opening_again_succeeds() {
+        let (client, mut server) = connect();
+        let doc = DocId(1);
+        let mut first = open(&client, &mut server, doc, FrameType::Ok)
+            .await
+            .unwrap();
+        let mut second = open(&client, &mut server, doc, FrameType::Ok)
+            .await
+            .unwrap();
+
+        server.send_presence(doc, 7);
+        assert_eq!(second.next().await.unwrap().client, 7);
+        assert!(first.next().await.is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! Hash chaining for the audit log.
+//!
+//! An entry's hash covers every other field, including the previous entry's hash. Changing or
+//! removing an entry therefore breaks the chain from that point on, which [`ChainVerifier`]
+//! detects. Keeping the last hash of each export lets a later export be checked against it.
+
+use crate::frame::AuditEntry;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter, Write};
+
+/// The `prev_hash` of the first entry.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The fields an entry's hash covers, in a fixed order.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Hashed<'a> {
+    seq: u64,
+    timestamp: u64,
+    actor: &'a str,
+    action: &'a str,
+    target: &'a Option<String>,
+    peer: &'a Option<String>,
+    details: &'a Option<serde_json::Value>,
+    prev_hash: &'a str,
+}
+
+/// Hex SHA-256 of every field of `entry` except `hash`.
+pub fn entry_hash(entry: &AuditEntry) -> String {
+    let hashed = Hashed {
+        seq: entry.seq,
+        timestamp: entry.timestamp,
+        actor: &entry.actor,
+        action: &entry.action,
+        target: &entry.target,
+        peer: &entry.peer,
+        details: &entry.details,
+        prev_hash: &entry.prev_hash,
+    };
+    let json = serde_json::to_vec(&hashed).expect("audit entries serialize");
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(json) {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Checks entries one at a time, in order.
+///
+/// If the first entry checked isn't the first in the log, its `prev_hash` is trusted.
+#[derive(Debug, Default)]
+pub struct ChainVerifier {
+    last: Option<(u64, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    /// The entry doesn't match its hash, so it was changed after being written
+    Hash { seq: u64 },
+    /// The entry doesn't follow the previous one, so entries were removed or reordered
+    Link { seq: u64 },
+}
+
+impl ChainVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, entry: &AuditEntry) -> Result<(), ChainError> {
+        if entry_hash(entry) != entry.hash {
+            return Err(ChainError::Hash { seq: entry.seq });
+        }
+        let linked = match &self.last {
+            Some((seq, hash)) => entry.seq == seq + 1 && &entry.prev_hash == hash,
+            None => entry.seq > 1 || entry.prev_hash == GENESIS_HASH,
+        };
+        if !linked {
+            return Err(ChainError::Link { seq: entry.seq });
+        }
+        self.last = Some((entry.seq, entry.hash.clone()));
+        Ok(())
+    }
+
+    /// The hash of the last entry checked.
+    pub fn last_hash(&self) -> Option<&str> {
+        self.last.as_ref().map(|(_, hash)| hash.as_str())
+    }
+}
+
+impl Display for ChainError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Hash { seq } => write!(f, "audit entry {} doesn't match its hash", seq),
+            ChainError::Link { seq } => {
+                write!(f, "audit entry {} doesn't follow the entry before it", seq)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A valid chain of `len` entries starting from the genesis hash.
+    fn chain(len: u64) -> Vec<AuditEntry> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        (1..=len)
+            .map(|seq| {
+                let mut entry = AuditEntry {
+                    seq,
+                    timestamp: 1_700_000_000 + seq,
+                    actor: "root".to_string(),
+                    action: "mintToken".to_string(),
+                    target: Some(format!("user{}", seq)),
+                    peer: Some("127.0.0.1:49243".to_string()),
+                    details: Some(serde_json::json!({ "lifetimeSeconds": 3600 })),
+                    prev_hash: prev_hash.clone(),
+                    hash: String::new(),
+                };
+                entry.hash = entry_hash(&entry);
+                prev_hash = entry.hash.clone();
+                entry
+            })
+            .collect()
+    }
+
+    fn verify(entries: &[AuditEntry]) -> Result<(), ChainError> {
+        let mut verifier = ChainVerifier::new();
+        entries.iter().try_for_each(|entry| verifier.push(entry))
+    }
+
+    #[test]
+    fn accepts_a_valid_chain() {
+        let entries = chain(5);
+        let mut verifier = ChainVerifier::new();
+        for entry in &entries {
+            verifier.push(entry).unwrap();
+        }
+        assert_eq!(verifier.last_hash(), Some(entries[4].hash.as_str()));
+    }
+
+    #[test]
+    fn accepts_a_chain_starting_later() {
+        let entries = chain(5);
+        assert_eq!(verify(&entries[2..]), Ok(()));
+    }
+
+    #[test]
+    fn detects_an_edited_entry() {
+        let mut entries = chain(5);
+        entries[2].target = Some("mallory".to_string());
+        assert_eq!(verify(&entries), Err(ChainError::Hash { seq: 3 }));
+    }
+
+    #[test]
+    fn detects_an_edited_entry_with_a_recomputed_hash() {
+        let mut entries = chain(5);
+        entries[2].target = Some("mallory".to_string());
+        entries[2].hash = entry_hash(&entries[2]);
+        assert_eq!(verify(&entries), Err(ChainError::Link { seq: 4 }));
+    }
+
+    #[test]
+    fn detects_a_removed_entry() {
+        let mut entries = chain(5);
+        entries.remove(2);
+        assert_eq!(verify(&entries), Err(ChainError::Link { seq: 4 }));
+    }
+
+    #[test]
+    fn detects_reordered_entries() {
+        let mut entries = chain(5);
+        entries.swap(1, 2);
+        assert_eq!(verify(&entries), Err(ChainError::Link { seq: 3 }));
+    }
+
+    #[test]
+    fn detects_a_bad_genesis() {
+        let mut entries = chain(3);
+        entries[0].prev_hash = "1".repeat(64);
+        entries[0].hash = entry_hash(&entries[0]);
+        assert_eq!(verify(&entries), Err(ChainError::Link { seq: 1 }));
+    }
+}
@@ -1,7 +1,8 @@
 use crate::frame::Frame;
 use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
 
-const MAX_LENGTH: usize = 1024 * 1024 * 16;
+/// The longest line, and so frame, the codec accepts.
+pub const MAX_LENGTH: usize = 1024 * 1024 * 16;
 
 pub struct ShrubCodec(LinesCodec);
 
@@ -19,9 +20,7 @@ impl Decoder for ShrubCodec {
         let Some(line) = self.0.decode(src).map_err(map_lines_err)? else {
             return Ok(None);
         };
-        serde_json::from_str(&line)
-            .map(Some)
-            .map_err(map_serde_json_err)
+        Frame::parse(&line).map(Some).map_err(map_serde_json_err)
     }
 }
 
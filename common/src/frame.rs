@@ -30,6 +30,35 @@ impl Frame {
             bulk: None,
         }
     }
+
+    /// Parses a frame from JSON. A frame whose payload doesn't match its type but which still
+    /// has an `id` parses as [`FrameType::Malformed`], so the peer can be told which frame was
+    /// rejected.
+    pub fn parse(text: &str) -> serde_json::Result<Frame> {
+        let err = match serde_json::from_str(text) {
+            Ok(frame) => return Ok(frame),
+            Err(err) => err,
+        };
+        let Ok(header) = serde_json::from_str::<FrameHeader>(text) else {
+            return Err(err);
+        };
+        Ok(Frame {
+            id: header.id,
+            reply_to: header.reply_to,
+            frame: FrameType::Malformed {
+                error: err.to_string(),
+            },
+            bulk: None,
+        })
+    }
+}
+
+/// The fields every frame has, whatever its type.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FrameHeader {
+    id: i32,
+    reply_to: Option<i32>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -39,9 +68,27 @@ pub enum FrameType {
     Authenticate {
         token: String,
     },
+    /// Sent by the client instead of `Authenticate` to resume a session whose connection
+    /// dropped, keeping its open docs and client ids.
+    Resume {
+        token: String,
+    },
+    /// Sent by the server after a successful `Authenticate` or `Resume`. The session can be
+    /// resumed with `token` for `window_seconds` after the connection drops. Each token can only
+    /// be used once.
+    ResumeToken {
+        token: String,
+        window_seconds: u64,
+    },
     Ok,
+    /// Reply to a request that failed. `error` is a human readable message, `code` is what
+    /// clients should match on.
     Error {
+        /// Missing from servers that predate error codes
+        #[serde(default)]
+        code: ErrorCode,
         error: String,
+        details: Option<serde_json::Value>,
     },
     MintToken {
         user: String,
@@ -54,6 +101,14 @@ pub enum FrameType {
     RevokeTokensForUser {
         user: String,
     },
+    CreateDoc,
+    GetDoc {
+        doc: DocId,
+    },
+    /// Reply to `CreateDoc` and `GetDoc`
+    DocInfo {
+        info: DocInfo,
+    },
     Open {
         doc: DocId,
     },
@@ -64,10 +119,127 @@ pub enum FrameType {
     Presence {
         updates: Vec<PresenceFrame>,
     },
+    GetServerInfo,
+    /// Reply to `GetServerInfo`, also sent by the server once a connection is authenticated
+    ServerInfo {
+        info: ServerInfo,
+    },
+    /// Lists the authenticated connections. Root only.
+    ListConnections,
+    /// Reply to `ListConnections`
+    ConnectionList {
+        connections: Vec<ConnectionInfo>,
+    },
+    /// Lists the docs with clients connected. Root only.
+    ListOpenDocs,
+    /// Reply to `ListOpenDocs`
+    OpenDocList {
+        docs: Vec<OpenDocInfo>,
+    },
+    /// Closes a connection without letting it resume its session. Root only.
+    KickConnection {
+        connection: u64,
+    },
+    /// Closes a doc a connection has open. Root only.
+    KickFromDoc {
+        connection: u64,
+        doc: DocId,
+    },
+    /// Lists audit log entries in order, starting after the entry numbered `after`. The server
+    /// returns at most `limit` entries and may return fewer. Root only.
+    QueryAuditLog {
+        after: Option<u64>,
+        limit: Option<usize>,
+    },
+    /// Reply to `QueryAuditLog`. Empty once there are no more entries.
+    AuditLog {
+        entries: Vec<AuditEntry>,
+    },
+    /// Sent by the server when an admin kicks the connection from `doc`, or from the server if
+    /// there's no `doc`, in which case the connection is closed and can't be resumed.
+    Kicked {
+        doc: Option<DocId>,
+    },
+    /// Sent by the server before it closes the connection to shut down. Clients should wait
+    /// `reconnect_after_seconds` before reconnecting.
+    Shutdown {
+        reconnect_after_seconds: u64,
+    },
+    /// Produced by [`Frame::parse`] for a frame that couldn't be decoded. Never sent.
+    #[serde(skip)]
+    Malformed {
+        error: String,
+    },
     #[serde(other)]
     UnknownFrame,
 }
 
+impl FrameType {
+    /// The frame's `type` as sent on the wire, or `malformed` and `unknown` for frames that
+    /// couldn't be decoded.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameType::Authenticate { .. } => "authenticate",
+            FrameType::Resume { .. } => "resume",
+            FrameType::ResumeToken { .. } => "resumeToken",
+            FrameType::Ok => "ok",
+            FrameType::Error { .. } => "error",
+            FrameType::MintToken { .. } => "mintToken",
+            FrameType::MintTokenResponse { .. } => "mintTokenResponse",
+            FrameType::RevokeTokensForUser { .. } => "revokeTokensForUser",
+            FrameType::CreateDoc => "createDoc",
+            FrameType::GetDoc { .. } => "getDoc",
+            FrameType::DocInfo { .. } => "docInfo",
+            FrameType::Open { .. } => "open",
+            FrameType::UpdatePresence { .. } => "updatePresence",
+            FrameType::Presence { .. } => "presence",
+            FrameType::GetServerInfo => "getServerInfo",
+            FrameType::ServerInfo { .. } => "serverInfo",
+            FrameType::ListConnections => "listConnections",
+            FrameType::ConnectionList { .. } => "connectionList",
+            FrameType::ListOpenDocs => "listOpenDocs",
+            FrameType::OpenDocList { .. } => "openDocList",
+            FrameType::KickConnection { .. } => "kickConnection",
+            FrameType::KickFromDoc { .. } => "kickFromDoc",
+            FrameType::Kicked { .. } => "kicked",
+            FrameType::QueryAuditLog { .. } => "queryAuditLog",
+            FrameType::AuditLog { .. } => "auditLog",
+            FrameType::Shutdown { .. } => "shutdown",
+            FrameType::Malformed { .. } => "malformed",
+            FrameType::UnknownFrame => "unknown",
+        }
+    }
+}
+
+/// Why a request failed. New codes may be added, which older clients decode as `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The token is invalid, expired or revoked
+    Unauthorized,
+    /// The authenticated user isn't allowed to do this
+    Forbidden,
+    NotFound,
+    BadRequest,
+    /// The frame refers to a doc this connection hasn't opened. `details` has the `doc`.
+    DocNotOpen,
+    /// The session can't be resumed, so the client should authenticate again
+    CannotResume,
+    /// The client is sending requests too quickly
+    RateLimited,
+    /// The frame couldn't be decoded
+    InvalidFrame,
+    /// The frame's type isn't one the server knows, likely because the client is newer
+    UnknownFrame,
+    /// The frame's type is only sent by the server, or isn't valid at this point
+    UnexpectedFrame,
+    Internal,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PresenceFrame {
@@ -77,3 +249,105 @@ pub struct PresenceFrame {
     pub info: Option<serde_json::Value>,
     pub presence: Option<serde_json::Value>,
 }
+
+/// What a server supports, so clients can work with servers older or newer than themselves.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    /// The server's build version
+    pub version: String,
+    /// Version messages the server accepts, such as `shrub1`
+    pub protocol_versions: Vec<String>,
+    /// How frames can be encoded, such as `json`
+    pub encodings: Vec<String>,
+    /// Optional protocol features enabled on this server, such as `resume`
+    pub features: Vec<String>,
+    pub limits: ServerLimits,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerLimits {
+    /// The longest frame the server accepts, in bytes of encoded JSON
+    pub max_frame_length: usize,
+    /// Frames that may be waiting to be sent to a connection before it's considered too slow
+    pub outbound_queue: usize,
+    /// Seconds presence is kept after its last update
+    pub presence_ttl_seconds: u64,
+    /// Seconds between rebroadcasts of everyone's presence in a doc
+    pub presence_interval_seconds: u64,
+    /// Seconds a dropped session can be resumed for, 0 if resumption is disabled
+    pub resume_window_seconds: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocInfo {
+    pub doc: DocId,
+    pub created_by: Option<String>,
+    /// Seconds since the unix epoch
+    pub created_at: Option<u64>,
+}
+
+/// An authenticated connection, as listed for admins.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub user: String,
+    /// How the connection reached the server, such as `shrub_tls` or `websocket`
+    pub transport: String,
+    /// The peer's address, if it connected over the network
+    pub peer: Option<String>,
+    pub open_docs: Vec<DocId>,
+    /// Seconds since the unix epoch
+    pub connected_at: u64,
+    pub queue: QueueInfo,
+}
+
+/// The state of a connection's outbound queue.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueInfo {
+    pub capacity: usize,
+    /// Frames currently waiting to be written
+    pub depth: usize,
+    /// The most frames that have been waiting at once
+    pub max_depth: usize,
+    pub sent: u64,
+    pub dropped_presence: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenDocInfo {
+    pub doc: DocId,
+    /// Connections with the doc open
+    pub clients: usize,
+}
+
+/// An administrative action, as recorded in the server's audit log.
+///
+/// Each entry includes the hash of the one before it, so removing or changing an entry breaks
+/// the chain. See [`crate::audit`].
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// Numbered from 1 without gaps
+    pub seq: u64,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    /// The user who took the action
+    pub actor: String,
+    /// The frame type of the action, such as `mintToken`
+    pub action: String,
+    /// The user, connection or doc acted on
+    pub target: Option<String>,
+    /// The actor's address, if they connected over the network
+    pub peer: Option<String>,
+    pub details: Option<serde_json::Value>,
+    /// Hex SHA-256 of the previous entry, or all zeros for the first
+    pub prev_hash: String,
+    /// Hex SHA-256 of this entry's other fields
+    pub hash: String,
+}
@@ -1,22 +1,30 @@
 use crate::codec::ShrubCodec;
 use crate::frame::Frame;
-use futures::{Sink, Stream, StreamExt};
+use crate::tls::{TlsAcceptor, TlsConnector, TlsStream};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use pin_project::pin_project;
 use std::fmt::Formatter;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio_native_tls::{TlsAcceptor, TlsStream};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_tungstenite::tungstenite::http::Uri;
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 use tokio_util::codec::{Decoder, Framed};
 
+/// A connection speaking frames over any of the supported transports.
+///
+/// `T` is the TLS stream used by the secure variants.
 #[pin_project(project = FramedConnectionProj)]
-pub enum FramedConnection {
+pub enum FramedConnection<T = TlsStream<TcpStream>> {
     Shrub(#[pin] Framed<TcpStream, ShrubCodec>),
-    ShrubSecure(#[pin] Framed<TlsStream<TcpStream>, ShrubCodec>),
+    ShrubSecure(#[pin] Framed<T, ShrubCodec>),
+    #[cfg(unix)]
+    ShrubUnix(#[pin] Framed<UnixStream, ShrubCodec>),
     WebSocket(#[pin] WebSocketStream<TcpStream>),
-    WebSocketSecure(#[pin] WebSocketStream<TlsStream<TcpStream>>),
+    WebSocketSecure(#[pin] WebSocketStream<T>),
 }
 
 impl FramedConnection {
@@ -34,11 +42,33 @@ impl FramedConnection {
         socket: TcpStream,
         acceptor: &TlsAcceptor,
     ) -> eyre::Result<Self> {
-        let mut socket = acceptor.accept(socket).await?;
-        read_shrub_version_header(&mut socket).await?;
+        let socket = acceptor.accept(socket).await?;
+        Self::accept_shrub_tls(socket).await
+    }
+
+    /// Connects over TLS, verifying the server's certificate is valid for `domain`.
+    pub async fn establish_shrub_secure(
+        socket: TcpStream,
+        connector: &TlsConnector,
+        domain: &str,
+    ) -> eyre::Result<Self> {
+        let mut socket = connector.connect(domain, socket).await?;
+        send_shrub_version_header(&mut socket).await?;
         Ok(Self::ShrubSecure(ShrubCodec::new().framed(socket)))
     }
 
+    #[cfg(unix)]
+    pub async fn accept_shrub_unix(mut socket: UnixStream) -> eyre::Result<Self> {
+        read_shrub_version_header(&mut socket).await?;
+        Ok(Self::ShrubUnix(ShrubCodec::new().framed(socket)))
+    }
+
+    #[cfg(unix)]
+    pub async fn establish_shrub_unix(mut socket: UnixStream) -> eyre::Result<Self> {
+        send_shrub_version_header(&mut socket).await?;
+        Ok(Self::ShrubUnix(ShrubCodec::new().framed(socket)))
+    }
+
     pub async fn accept_websocket(socket: TcpStream) -> eyre::Result<Self> {
         let mut socket = tokio_tungstenite::accept_async(socket).await?;
         read_websocket_version_message(&mut socket).await?;
@@ -50,19 +80,64 @@ impl FramedConnection {
         acceptor: &TlsAcceptor,
     ) -> eyre::Result<Self> {
         let socket = acceptor.accept(socket).await?;
+        Self::accept_websocket_tls(socket).await
+    }
+
+    /// Connects to the websocket endpoint of the server at a `ws://` URL such as
+    /// `ws://localhost:8080`.
+    pub async fn establish_websocket(url: &str) -> eyre::Result<Self> {
+        let (uri, host, port) = websocket_endpoint(url, "ws", 80)?;
+        let socket = TcpStream::connect((host.as_str(), port)).await?;
+        let (mut socket, _) = tokio_tungstenite::client_async(uri, socket).await?;
+        send_websocket_version_message(&mut socket).await?;
+        Ok(Self::WebSocket(socket))
+    }
+
+    /// Connects to the websocket endpoint of the server at a `wss://` URL, verifying the
+    /// server's certificate is valid for the URL's host.
+    pub async fn establish_websocket_secure(
+        url: &str,
+        connector: &TlsConnector,
+    ) -> eyre::Result<Self> {
+        let (uri, host, port) = websocket_endpoint(url, "wss", 443)?;
+        let socket = TcpStream::connect((host.as_str(), port)).await?;
+        let socket = connector.connect(&host, socket).await?;
+        let (mut socket, _) = tokio_tungstenite::client_async(uri, socket).await?;
+        send_websocket_version_message(&mut socket).await?;
+        Ok(Self::WebSocketSecure(socket))
+    }
+}
+
+impl<T> FramedConnection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Accepts the shrub protocol over a TLS stream that has already completed its handshake.
+    pub async fn accept_shrub_tls(mut socket: T) -> eyre::Result<Self> {
+        read_shrub_version_header(&mut socket).await?;
+        Ok(Self::ShrubSecure(ShrubCodec::new().framed(socket)))
+    }
+
+    /// Accepts a websocket over a TLS stream that has already completed its handshake.
+    pub async fn accept_websocket_tls(socket: T) -> eyre::Result<Self> {
         let mut socket = tokio_tungstenite::accept_async(socket).await?;
         read_websocket_version_message(&mut socket).await?;
         Ok(Self::WebSocketSecure(socket))
     }
 }
 
-impl Stream for FramedConnection {
+impl<T> Stream for FramedConnection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
     type Item = Result<Frame, std::io::Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match self.project() {
             FramedConnectionProj::Shrub(inner) => inner.poll_next(cx),
             FramedConnectionProj::ShrubSecure(inner) => inner.poll_next(cx),
+            #[cfg(unix)]
+            FramedConnectionProj::ShrubUnix(inner) => inner.poll_next(cx),
             FramedConnectionProj::WebSocket(inner) => poll_websocket_next(inner, cx),
             FramedConnectionProj::WebSocketSecure(inner) => poll_websocket_next(inner, cx),
         }
@@ -95,7 +170,7 @@ where
         }
         _ => return Poll::Pending,
     };
-    let frame = match serde_json::from_str(&msg) {
+    let frame = match Frame::parse(&msg) {
         Ok(frame) => frame,
         Err(err) => {
             return Poll::Ready(Some(Err(std::io::Error::new(
@@ -107,13 +182,18 @@ where
     Poll::Ready(Some(Ok(frame)))
 }
 
-impl Sink<Frame> for FramedConnection {
+impl<T> Sink<Frame> for FramedConnection<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
     type Error = std::io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         match self.project() {
             FramedConnectionProj::Shrub(inner) => inner.poll_ready(cx),
             FramedConnectionProj::ShrubSecure(inner) => inner.poll_ready(cx),
+            #[cfg(unix)]
+            FramedConnectionProj::ShrubUnix(inner) => inner.poll_ready(cx),
             FramedConnectionProj::WebSocket(inner) => {
                 map_tungstenite_poll_result(inner.poll_ready(cx))
             }
@@ -127,6 +207,8 @@ impl Sink<Frame> for FramedConnection {
         match self.project() {
             FramedConnectionProj::Shrub(inner) => inner.start_send(item),
             FramedConnectionProj::ShrubSecure(inner) => inner.start_send(item),
+            #[cfg(unix)]
+            FramedConnectionProj::ShrubUnix(inner) => inner.start_send(item),
             FramedConnectionProj::WebSocket(inner) => map_tungstenite_result(
                 inner.start_send(tungstenite::Message::Text(serde_json::to_string(&item)?)),
             ),
@@ -140,6 +222,8 @@ impl Sink<Frame> for FramedConnection {
         match self.project() {
             FramedConnectionProj::Shrub(inner) => inner.poll_flush(cx),
             FramedConnectionProj::ShrubSecure(inner) => inner.poll_flush(cx),
+            #[cfg(unix)]
+            FramedConnectionProj::ShrubUnix(inner) => inner.poll_flush(cx),
             FramedConnectionProj::WebSocket(inner) => {
                 map_tungstenite_poll_result(inner.poll_flush(cx))
             }
@@ -153,6 +237,8 @@ impl Sink<Frame> for FramedConnection {
         match self.project() {
             FramedConnectionProj::Shrub(inner) => inner.poll_close(cx),
             FramedConnectionProj::ShrubSecure(inner) => inner.poll_close(cx),
+            #[cfg(unix)]
+            FramedConnectionProj::ShrubUnix(inner) => inner.poll_close(cx),
             FramedConnectionProj::WebSocket(inner) => {
                 map_tungstenite_poll_result(inner.poll_close(cx))
             }
@@ -176,7 +262,7 @@ fn map_tungstenite_result<T>(res: Result<T, tungstenite::Error>) -> Result<T, st
     match res {
         Ok(val) => Ok(val),
         Err(tungstenite::Error::Io(err)) => Err(err),
-        Err(err) => Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
+        Err(err) => Err(std::io::Error::other(err)),
     }
 }
 
@@ -199,6 +285,45 @@ where
     socket.write_all(b"shrub1\n").await
 }
 
+/// The `/socket` URI on the server at `url`, and the host and port to connect to. An IPv6 host
+/// is returned without its brackets, as needed to connect and to verify the certificate.
+fn websocket_endpoint(
+    url: &str,
+    scheme: &str,
+    default_port: u16,
+) -> eyre::Result<(Uri, String, u16)> {
+    let url: Uri = url.parse()?;
+    if url.scheme_str() != Some(scheme) {
+        return Err(eyre::eyre!("expected a {}:// URL", scheme));
+    }
+    let Some(authority) = url.authority() else {
+        return Err(eyre::eyre!("expected a host in the URL"));
+    };
+    let host = authority.host();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+        .to_string();
+    let port = authority.port_u16().unwrap_or(default_port);
+    let uri = Uri::builder()
+        .scheme(scheme)
+        .authority(authority.clone())
+        .path_and_query("/socket")
+        .build()?;
+    Ok((uri, host, port))
+}
+
+async fn send_websocket_version_message<T>(mut socket: T) -> eyre::Result<()>
+where
+    T: Unpin + Sink<tungstenite::Message, Error = tungstenite::Error>,
+{
+    socket
+        .send(tungstenite::Message::Text("shrub1\n".to_string()))
+        .await?;
+    Ok(())
+}
+
 async fn read_websocket_version_message<T>(mut socket: T) -> eyre::Result<()>
 where
     T: Unpin + Stream<Item = Result<tungstenite::Message, tungstenite::Error>>,
@@ -222,7 +347,7 @@ where
     Ok(())
 }
 
-impl std::fmt::Debug for FramedConnection {
+impl<T> std::fmt::Debug for FramedConnection<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("FramedConnection").finish_non_exhaustive()
     }
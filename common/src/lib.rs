@@ -3,12 +3,15 @@ use std::fmt::{Debug, Display};
 use std::str::FromStr;
 use ulid::Ulid;
 
+pub mod audit;
 pub mod frame;
 
 #[cfg(feature = "full")]
 pub mod codec;
 #[cfg(feature = "full")]
 pub mod framed;
+#[cfg(feature = "full")]
+pub mod tls;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct DocId(pub u128);
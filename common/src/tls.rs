@@ -0,0 +1,296 @@
+//! TLS backends. `native-tls` is always available, rustls is behind the `rustls` feature.
+
+use pin_project::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_native_tls::native_tls;
+
+/// ALPN protocol ids offered by the rustls backend, in order of preference.
+pub const ALPN_PROTOCOLS: &[&[u8]] = &[ALPN_SHRUB, ALPN_HTTP];
+
+pub const ALPN_SHRUB: &[u8] = b"shrub1";
+
+pub const ALPN_HTTP: &[u8] = b"http/1.1";
+
+#[derive(Clone)]
+pub enum TlsAcceptor {
+    Native(tokio_native_tls::TlsAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsAcceptor),
+}
+
+/// The connecting side of either TLS backend.
+#[derive(Clone)]
+pub enum TlsConnector {
+    Native(tokio_native_tls::TlsConnector),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsConnector),
+}
+
+#[pin_project(project = TlsStreamProj)]
+pub enum TlsStream<S> {
+    Native(#[pin] tokio_native_tls::TlsStream<S>),
+    #[cfg(feature = "rustls")]
+    Rustls(#[pin] tokio_rustls::server::TlsStream<S>),
+    #[cfg(feature = "rustls")]
+    RustlsClient(#[pin] tokio_rustls::client::TlsStream<S>),
+}
+
+impl TlsAcceptor {
+    /// Loads a PKCS#12 identity for `native-tls`.
+    pub fn native_from_pkcs12(der: &[u8], password: &str) -> eyre::Result<Self> {
+        let identity = native_tls::Identity::from_pkcs12(der, password)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)?;
+        Ok(Self::Native(acceptor.into()))
+    }
+
+    /// Loads a PEM certificate chain and private key (PKCS#8, PKCS#1 or SEC1) for rustls.
+    ///
+    /// If `client_ca` is given, clients may present a certificate signed by one of the PEM
+    /// certificates it contains. Clients without a certificate are still accepted.
+    #[cfg(feature = "rustls")]
+    pub fn rustls_from_pem(
+        cert_chain: &[u8],
+        key: &[u8],
+        client_ca: Option<&[u8]>,
+    ) -> eyre::Result<Self> {
+        use std::sync::Arc;
+        use tokio_rustls::rustls;
+
+        let certs = read_pem_certs(cert_chain)?;
+        let key = read_pem_private_key(key)?;
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let mut roots = rustls::RootCertStore::empty();
+                for cert in read_pem_certs(client_ca)? {
+                    roots.add(&cert)?;
+                }
+                builder.with_client_cert_verifier(
+                    rustls::server::AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+        Ok(Self::Rustls(Arc::new(config).into()))
+    }
+
+    pub async fn accept<S>(&self, socket: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            TlsAcceptor::Native(acceptor) => acceptor
+                .accept(socket)
+                .await
+                .map(TlsStream::Native)
+                .map_err(io::Error::other),
+            #[cfg(feature = "rustls")]
+            TlsAcceptor::Rustls(acceptor) => acceptor.accept(socket).await.map(TlsStream::Rustls),
+        }
+    }
+}
+
+impl TlsConnector {
+    /// Trusts the system's root certificates, plus the PEM certificate `root_ca` if given.
+    pub fn native(root_ca: Option<&[u8]>) -> eyre::Result<Self> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(root_ca) = root_ca {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(root_ca)?);
+        }
+        Ok(Self::Native(builder.build()?.into()))
+    }
+
+    /// Trusts only the PEM certificates in `root_ca`, as rustls has no access to the system's
+    /// root certificates.
+    #[cfg(feature = "rustls")]
+    pub fn rustls_from_pem(root_ca: &[u8]) -> eyre::Result<Self> {
+        use std::sync::Arc;
+        use tokio_rustls::rustls;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in read_pem_certs(root_ca)? {
+            roots.add(&cert)?;
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self::Rustls(Arc::new(config).into()))
+    }
+
+    /// Performs the handshake, verifying the server's certificate is valid for `domain`.
+    pub async fn connect<S>(&self, domain: &str, socket: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            TlsConnector::Native(connector) => connector
+                .connect(domain, socket)
+                .await
+                .map(TlsStream::Native)
+                .map_err(io::Error::other),
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(connector) => {
+                let domain = tokio_rustls::rustls::ServerName::try_from(domain)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                connector
+                    .connect(domain, socket)
+                    .await
+                    .map(TlsStream::RustlsClient)
+            }
+        }
+    }
+}
+
+impl<S> TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// The DER encoded certificate the peer presented, if any.
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        match self {
+            TlsStream::Native(inner) => inner
+                .get_ref()
+                .peer_certificate()
+                .ok()
+                .flatten()
+                .and_then(|cert| cert.to_der().ok()),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(inner) => inner
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.0.clone()),
+            #[cfg(feature = "rustls")]
+            TlsStream::RustlsClient(inner) => inner
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.0.clone()),
+        }
+    }
+
+    /// The protocol negotiated with ALPN, if any. Only the rustls backend supports ALPN.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            TlsStream::Native(_) => None,
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(inner) => inner.get_ref().1.alpn_protocol(),
+            #[cfg(feature = "rustls")]
+            TlsStream::RustlsClient(inner) => inner.get_ref().1.alpn_protocol(),
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn read_pem_certs(pem: &[u8]) -> eyre::Result<Vec<tokio_rustls::rustls::Certificate>> {
+    let certs = rustls_pemfile::certs(&mut &*pem)?;
+    if certs.is_empty() {
+        return Err(eyre::eyre!("no certificates found in PEM"));
+    }
+    Ok(certs
+        .into_iter()
+        .map(tokio_rustls::rustls::Certificate)
+        .collect())
+}
+
+#[cfg(feature = "rustls")]
+fn read_pem_private_key(pem: &[u8]) -> eyre::Result<tokio_rustls::rustls::PrivateKey> {
+    use rustls_pemfile::Item;
+    let mut reader = pem;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(tokio_rustls::rustls::PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(eyre::eyre!("no private key found in PEM"))
+}
+
+impl<S> AsyncRead for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.project() {
+            TlsStreamProj::Native(inner) => inner.poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::Rustls(inner) => inner.poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::RustlsClient(inner) => inner.poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.project() {
+            TlsStreamProj::Native(inner) => inner.poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::Rustls(inner) => inner.poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::RustlsClient(inner) => inner.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            TlsStreamProj::Native(inner) => inner.poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::Rustls(inner) => inner.poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::RustlsClient(inner) => inner.poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.project() {
+            TlsStreamProj::Native(inner) => inner.poll_shutdown(cx),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::Rustls(inner) => inner.poll_shutdown(cx),
+            #[cfg(feature = "rustls")]
+            TlsStreamProj::RustlsClient(inner) => inner.poll_shutdown(cx),
+        }
+    }
+}
+
+impl std::fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsAcceptor::Native(_) => f.write_str("TlsAcceptor::Native"),
+            #[cfg(feature = "rustls")]
+            TlsAcceptor::Rustls(_) => f.write_str("TlsAcceptor::Rustls"),
+        }
+    }
+}
+
+impl std::fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsConnector::Native(_) => f.write_str("TlsConnector::Native"),
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(_) => f.write_str("TlsConnector::Rustls"),
+        }
+    }
+}
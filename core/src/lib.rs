@@ -0,0 +1,200 @@
+//! The protocol core for browser clients, built for `wasm32-unknown-unknown` and served by the
+//! server as `core.wasm`.
+//!
+//! The module has no imports, so it can be instantiated as is with
+//! `WebAssembly.instantiateStreaming(fetch("/core.wasm"))`. Its exports use a plain C ABI:
+//!
+//! - Text is UTF-8. To pass some in, JavaScript copies it into a buffer from `alloc(len)`, passes
+//!   the pointer and length, and frees the buffer with `dealloc(ptr, len)` afterwards.
+//! - Functions producing text return 0 on success and 1 on error. Either way the text, or the
+//!   error message, is then `output_len()` bytes at `output_ptr()` until the next call.
+//!   [`decode_frame`] can also return 2, for a malformed frame.
+//!
+//! Frames cross as JSON in the same shape as on the wire, and are checked and serialized by the
+//! same `shrubbery-common` types the server uses.
+
+use shrubbery_common::frame::{Frame, FrameType};
+use shrubbery_common::DocId;
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::str::FromStr;
+
+const OK: u32 = 0;
+const ERROR: u32 = 1;
+const MALFORMED: u32 = 2;
+
+/// The version message a client sends before any frames, including over websockets.
+const PROTOCOL_VERSION: &[u8] = b"shrub1\n";
+
+thread_local! {
+    /// The text produced by the last call.
+    static OUTPUT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Allocates a buffer of `len` bytes to pass text in.
+#[no_mangle]
+pub extern "C" fn alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::<u8>::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    std::mem::forget(buf);
+    ptr
+}
+
+/// Frees a buffer from [`alloc`].
+///
+/// # Safety
+///
+/// `ptr` and `len` must be from the same call to [`alloc`], and the buffer not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn dealloc(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+#[no_mangle]
+pub extern "C" fn output_ptr() -> *const u8 {
+    OUTPUT.with(|output| output.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn output_len() -> usize {
+    OUTPUT.with(|output| output.borrow().len())
+}
+
+/// The version message is `protocol_version_len()` bytes at `protocol_version_ptr()`.
+#[no_mangle]
+pub extern "C" fn protocol_version_ptr() -> *const u8 {
+    PROTOCOL_VERSION.as_ptr()
+}
+
+#[no_mangle]
+pub extern "C" fn protocol_version_len() -> usize {
+    PROTOCOL_VERSION.len()
+}
+
+/// Checks a frame to send, given as JSON, and outputs it encoded as the text of a websocket
+/// message. Unlike [`decode_frame`], a payload that doesn't match its type is an error, as the
+/// server would reject the frame.
+///
+/// # Safety
+///
+/// `ptr` must point to `len` initialized bytes.
+#[no_mangle]
+pub unsafe extern "C" fn encode_frame(ptr: *const u8, len: usize) -> u32 {
+    finish(read_input(ptr, len).and_then(|json| {
+        let frame: Frame = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        serde_json::to_string(&frame).map_err(|err| err.to_string())
+    }))
+}
+
+/// Decodes the text of a websocket message as the server does with [`Frame::parse`], and
+/// outputs the frame as JSON.
+///
+/// A frame whose payload doesn't match its type but which has an `id` returns 2 rather than 1,
+/// with `{"id", "replyTo", "error"}` output so the client can tell which frame it rejected.
+///
+/// # Safety
+///
+/// `ptr` must point to `len` initialized bytes.
+#[no_mangle]
+pub unsafe extern "C" fn decode_frame(ptr: *const u8, len: usize) -> u32 {
+    let frame = match read_input(ptr, len)
+        .and_then(|text| Frame::parse(text).map_err(|err| err.to_string()))
+    {
+        Ok(frame) => frame,
+        Err(err) => return finish(Err(err)),
+    };
+    match frame.frame {
+        FrameType::Malformed { error } => {
+            let malformed = serde_json::json!({
+                "id": frame.id,
+                "replyTo": frame.reply_to,
+                "error": error,
+            });
+            set_output(malformed.to_string());
+            MALFORMED
+        }
+        _ => finish(serde_json::to_string(&frame)),
+    }
+}
+
+/// Parses a doc id and outputs it in canonical form.
+///
+/// # Safety
+///
+/// `ptr` must point to `len` initialized bytes.
+#[no_mangle]
+pub unsafe extern "C" fn parse_doc_id(ptr: *const u8, len: usize) -> u32 {
+    finish(read_input(ptr, len).and_then(|doc| {
+        DocId::from_str(doc)
+            .map(|doc| doc.to_string())
+            .map_err(|err| err.to_string())
+    }))
+}
+
+/// Hands out the negative ids client frames are numbered with, so replies can be matched to
+/// requests by their `replyTo`.
+pub struct FrameIds {
+    next: i32,
+}
+
+impl FrameIds {
+    pub fn new() -> Self {
+        Self { next: -1 }
+    }
+
+    pub fn next_id(&mut self) -> i32 {
+        let id = self.next;
+        self.next -= 1;
+        id
+    }
+}
+
+impl Default for FrameIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn frame_ids_new() -> *mut FrameIds {
+    Box::into_raw(Box::new(FrameIds::new()))
+}
+
+/// # Safety
+///
+/// `ids` must be from [`frame_ids_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn frame_ids_next(ids: *mut FrameIds) -> i32 {
+    (*ids).next_id()
+}
+
+/// # Safety
+///
+/// `ids` must be from [`frame_ids_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn frame_ids_free(ids: *mut FrameIds) {
+    drop(Box::from_raw(ids));
+}
+
+unsafe fn read_input<'a>(ptr: *const u8, len: usize) -> Result<&'a str, String> {
+    let bytes = std::slice::from_raw_parts(ptr, len);
+    std::str::from_utf8(bytes).map_err(|err| err.to_string())
+}
+
+/// Stores the text or error message for `output_ptr` and returns the status.
+fn finish<E: Display>(res: Result<String, E>) -> u32 {
+    match res {
+        Ok(text) => {
+            set_output(text);
+            OK
+        }
+        Err(err) => {
+            set_output(err.to_string());
+            ERROR
+        }
+    }
+}
+
+fn set_output(text: String) {
+    OUTPUT.with(|output| *output.borrow_mut() = text);
+}
@@ -0,0 +1,137 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const WASM_TARGET: &str = "wasm32-unknown-unknown";
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    write_static_bundle(&out_dir);
+    write_core_wasm(&out_dir);
+}
+
+/// Generates the `core.wasm` to embed for `include!`, as an `Option<&[u8]>`.
+///
+/// Embedding it is opt-in: either the file in `SHRUB_CORE_WASM`, or with the `core-wasm` feature
+/// a build of `shrubbery-core`, which fails the build if that isn't possible. Otherwise the server
+/// is built without `core.wasm`, and requests for it get a 404.
+fn write_core_wasm(out_dir: &Path) {
+    println!("cargo:rerun-if-env-changed=SHRUB_CORE_WASM");
+    let wasm = match env::var_os("SHRUB_CORE_WASM") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if env::var_os("CARGO_FEATURE_CORE_WASM").is_some() => {
+            Some(build_core_wasm(out_dir))
+        }
+        None => {
+            println!(
+                "cargo:warning=building without core.wasm. Enable the core-wasm feature or set \
+                 SHRUB_CORE_WASM to a prebuilt core.wasm to embed it"
+            );
+            None
+        }
+    };
+
+    let out = match wasm {
+        Some(path) => {
+            let path = path.canonicalize().unwrap();
+            println!("cargo:rerun-if-changed={}", path.display());
+            format!("Some(include_bytes!({:?}))", path)
+        }
+        None => "None".to_string(),
+    };
+    fs::write(out_dir.join("core_wasm.rs"), out).unwrap();
+}
+
+fn build_core_wasm(out_dir: &Path) -> PathBuf {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let workspace = manifest_dir.parent().unwrap();
+    for dir in ["core", "common"] {
+        println!("cargo:rerun-if-changed={}", workspace.join(dir).display());
+    }
+
+    if !wasm_target_installed() {
+        panic!(
+            "the {target} target isn't installed, so core.wasm can't be built. Install it with \
+             `rustup target add {target}`, or set SHRUB_CORE_WASM to a prebuilt core.wasm",
+            target = WASM_TARGET
+        );
+    }
+
+    // A separate target dir, as the outer build holds the lock on the workspace's
+    let target_dir = out_dir.join("core-wasm");
+    let cargo = env::var_os("CARGO").unwrap();
+    let status = Command::new(cargo)
+        .current_dir(workspace)
+        .args([
+            "build",
+            "--release",
+            "--package",
+            "shrubbery-core",
+            "--target",
+        ])
+        .arg(WASM_TARGET)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .status()
+        .unwrap();
+    if !status.success() {
+        panic!("failed to build shrubbery-core for core.wasm");
+    }
+    target_dir
+        .join(WASM_TARGET)
+        .join("release")
+        .join("shrubbery_core.wasm")
+}
+
+fn wasm_target_installed() -> bool {
+    let rustc = env::var_os("RUSTC").unwrap();
+    let Ok(output) = Command::new(rustc).args(["--print", "sysroot"]).output() else {
+        return false;
+    };
+    let sysroot = String::from_utf8_lossy(&output.stdout);
+    Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(WASM_TARGET)
+        .exists()
+}
+
+/// Generates the list of files embedded from `SHRUB_STATIC_BUNDLE` for `include!`.
+fn write_static_bundle(out_dir: &Path) {
+    println!("cargo:rerun-if-env-changed=SHRUB_STATIC_BUNDLE");
+    let mut files = Vec::new();
+    if let Some(root) = env::var_os("SHRUB_STATIC_BUNDLE") {
+        let root = PathBuf::from(root);
+        println!("cargo:rerun-if-changed={}", root.display());
+        collect_files(&root, &root, &mut files);
+    }
+    files.sort();
+
+    let mut out = String::from("&[\n");
+    for (relative, path) in files {
+        let path = path.canonicalize().unwrap();
+        println!("cargo:rerun-if-changed={}", path.display());
+        writeln!(out, "    ({:?}, include_bytes!({:?})),", relative, path).unwrap();
+    }
+    out.push(']');
+    fs::write(out_dir.join("static_bundle.rs"), out).unwrap();
+}
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(root, &path, files);
+        } else {
+            let relative = path.strip_prefix(root).unwrap();
+            let relative = relative
+                .components()
+                .map(|c| c.as_os_str().to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((relative, path));
+        }
+    }
+}
@@ -0,0 +1,664 @@
+//! The server's settings, read from a TOML file passed with `--config`.
+//!
+//! Every setting has a command line flag, which can also be set with a `SHRUB_*` environment
+//! variable. Flags and variables override the file, which overrides the defaults here.
+
+use crate::db::StorageConfig;
+use crate::doc_manager::{self, PresenceConfig};
+use crate::proto::outbound::{OutboundConfig, OverflowPolicy};
+use crate::tls::TlsIdentity;
+use eyre::{eyre, WrapErr};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub data_dir: Option<PathBuf>,
+    /// Unix domain socket for local administration
+    pub admin_socket: Option<PathBuf>,
+    /// Written with the address each listener bound, one per line in the form of `--listen`
+    pub listeners_file: Option<PathBuf>,
+    pub log_format: LogFormat,
+    /// Any of these can be left out. An empty list serves only the admin socket
+    pub listeners: Vec<ListenerConfig>,
+    pub tls: TlsConfig,
+    pub http: HttpConfig,
+    pub limits: LimitsConfig,
+    pub presence: PresenceSettings,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+/// A TCP port to accept connections on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub protocol: ListenerProtocol,
+    /// Port 0 binds any free port, which is logged and written to `listeners_file`
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub tls: bool,
+    /// Only accept IPv6 on an IPv6 address. Otherwise `[::]` accepts IPv4 too where the OS allows
+    #[serde(default)]
+    pub ipv6_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListenerProtocol {
+    /// The shrubbery protocol
+    Shrub,
+    /// HTTP, websockets and the REST API
+    Http,
+    /// Every protocol, with or without TLS, told apart by the first bytes each client sends
+    Unified,
+    /// Only `/metrics` and the health endpoints
+    Metrics,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// A human readable line per event
+    Text,
+    /// A JSON object per event, with the spans it happened in, for log pipelines to index
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PKCS12 identity, used with `identity_password` or `identity_password_file`
+    pub identity: Option<PathBuf>,
+    pub identity_password: Option<Secret>,
+    pub identity_password_file: Option<PathBuf>,
+    /// PEM certificate chain, used with `key` instead of `identity`
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+    pub client_users: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub static_dir: Option<PathBuf>,
+    pub websocket_compression: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub outbound_queue: usize,
+    /// Seconds
+    pub write_timeout: u64,
+    pub outbound_overflow: OverflowPolicy,
+    pub overload_sessions: Option<usize>,
+    /// Seconds, 0 disables resumption
+    pub resume_window: u64,
+}
+
+/// Presence timings in seconds.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceSettings {
+    pub ttl: u64,
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Defaults to `root_token` in the data directory
+    pub root_token_file: Option<PathBuf>,
+    pub metrics_token: Option<Secret>,
+    pub metrics_token_file: Option<PathBuf>,
+}
+
+/// Timings in seconds.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long readiness fails before connections are closed, so load balancers stop sending
+    /// new clients first
+    pub pre_drain: u64,
+    pub timeout: u64,
+    pub reconnect_hint: u64,
+}
+
+/// A setting that mustn't be logged, such as a password. Serializes as `<redacted>` so the
+/// effective config can be logged as is.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Config {
+    /// Reads the config file at `path`. Settings missing from it keep their defaults.
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&text).wrap_err_with(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// The config as TOML with secrets redacted, for logging.
+    pub fn to_redacted_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_else(|err| format!("<failed to serialize: {}>", err))
+    }
+
+    /// Rejects settings the server can't run with, naming the offending key.
+    pub fn validate(&self) -> eyre::Result<()> {
+        if self.presence.interval < 1 {
+            return Err(eyre!("presence.interval must be at least 1"));
+        }
+        if self.presence.ttl < 1 {
+            return Err(eyre!("presence.ttl must be at least 1"));
+        }
+        if self.presence.ttl < self.presence.interval {
+            return Err(eyre!(
+                "presence.ttl ({}) must be at least presence.interval ({})",
+                self.presence.ttl,
+                self.presence.interval
+            ));
+        }
+        if self.limits.outbound_queue < 1 {
+            return Err(eyre!("limits.outbound_queue must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn outbound(&self) -> OutboundConfig {
+        OutboundConfig {
+            capacity: self.limits.outbound_queue,
+            write_timeout: Duration::from_secs(self.limits.write_timeout),
+            overflow: self.limits.outbound_overflow,
+        }
+    }
+
+    pub fn presence(&self) -> PresenceConfig {
+        PresenceConfig {
+            ttl: Duration::from_secs(self.presence.ttl),
+            interval: Duration::from_secs(self.presence.interval),
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn new(protocol: ListenerProtocol, address: SocketAddr, tls: bool) -> Self {
+        Self {
+            protocol,
+            address,
+            tls,
+            ipv6_only: false,
+        }
+    }
+
+    pub fn validate(&self) -> eyre::Result<()> {
+        match (self.protocol, self.tls) {
+            (ListenerProtocol::Unified, true) => Err(eyre!(
+                "unified listener {} detects TLS itself, so tls can't be set",
+                self.address
+            )),
+            (ListenerProtocol::Metrics, true) => Err(eyre!(
+                "metrics listener {} doesn't support TLS",
+                self.address
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn scheme(&self) -> &'static str {
+        match (self.protocol, self.tls) {
+            (ListenerProtocol::Shrub, false) => "shrub",
+            (ListenerProtocol::Shrub, true) => "shrubs",
+            (ListenerProtocol::Http, false) => "http",
+            (ListenerProtocol::Http, true) => "https",
+            (ListenerProtocol::Unified, _) => "unified",
+            (ListenerProtocol::Metrics, _) => "metrics",
+        }
+    }
+}
+
+/// Parses `<scheme>://<address>`, where the scheme is one of `shrub`, `shrubs`, `http`,
+/// `https`, `unified` or `metrics`, such as `shrubs://[::]:49244`.
+impl FromStr for ListenerConfig {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let (scheme, address) = s
+            .split_once("://")
+            .ok_or_else(|| eyre!("expected <scheme>://<address>, got {}", s))?;
+        let (protocol, tls) = match scheme {
+            "shrub" => (ListenerProtocol::Shrub, false),
+            "shrubs" => (ListenerProtocol::Shrub, true),
+            "http" => (ListenerProtocol::Http, false),
+            "https" => (ListenerProtocol::Http, true),
+            "unified" => (ListenerProtocol::Unified, false),
+            "metrics" => (ListenerProtocol::Metrics, false),
+            _ => return Err(eyre!("unknown listener scheme {}", scheme)),
+        };
+        let address = address
+            .parse()
+            .wrap_err_with(|| format!("invalid listener address {}", address))?;
+        Ok(Self::new(protocol, address, tls))
+    }
+}
+
+impl Display for ListenerConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", self.scheme(), self.address)
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(eyre!("expected a log format of text or json")),
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn identity(&self) -> eyre::Result<TlsIdentity> {
+        if self.client_ca.is_some() != self.client_users.is_some() {
+            return Err(eyre!(
+                "a TLS client CA and client users must be set together"
+            ));
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                if self.identity.is_some() {
+                    return Err(eyre!("a TLS cert can't be used with a PKCS12 identity"));
+                }
+                return Ok(TlsIdentity::Pem {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                    client_ca: self.client_ca.clone(),
+                });
+            }
+            (Some(_), None) => return Err(eyre!("a TLS cert requires a key")),
+            (None, Some(_)) => return Err(eyre!("a TLS key requires a cert")),
+            (None, None) => {}
+        }
+        if self.client_ca.is_some() {
+            return Err(eyre!("a TLS client CA requires a TLS cert and key"));
+        }
+
+        if let Some(path) = &self.identity {
+            let password = read_secret(&self.identity_password, &self.identity_password_file)?
+                .ok_or_else(|| eyre!("a TLS identity requires its password"))?;
+            Ok(TlsIdentity::Pkcs12 {
+                path: path.clone(),
+                password: password.0,
+            })
+        } else {
+            warn!("No TLS identity provided, using self-signed identity");
+            Ok(TlsIdentity::SelfSigned)
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn metrics_token(&self) -> eyre::Result<Option<Secret>> {
+        read_secret(&self.metrics_token, &self.metrics_token_file)
+    }
+}
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Returns the secret set directly or read from `file`, trimming surrounding whitespace such as
+/// a trailing newline from the file.
+fn read_secret(value: &Option<Secret>, file: &Option<PathBuf>) -> eyre::Result<Option<Secret>> {
+    match (value, file) {
+        (Some(_), Some(path)) => Err(eyre!(
+            "a secret is set both directly and from {}",
+            path.display()
+        )),
+        (Some(value), None) => Ok(Some(value.clone())),
+        (None, Some(path)) => {
+            let contents = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+            Ok(Some(Secret(contents.trim().to_string())))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+impl Default for Config {
+    /// Listens for each protocol on its own port. HTTP stays off 80 and 443 so the server can run
+    /// unprivileged.
+    fn default() -> Self {
+        let any = |port| SocketAddr::from(([0, 0, 0, 0], port));
+        Self {
+            data_dir: None,
+            admin_socket: None,
+            listeners_file: None,
+            log_format: LogFormat::Text,
+            listeners: vec![
+                ListenerConfig::new(ListenerProtocol::Shrub, any(49243), false),
+                ListenerConfig::new(ListenerProtocol::Shrub, any(49244), true),
+                ListenerConfig::new(ListenerProtocol::Http, any(8080), false),
+                ListenerConfig::new(ListenerProtocol::Http, any(8443), true),
+            ],
+            tls: TlsConfig::default(),
+            http: HttpConfig::default(),
+            limits: LimitsConfig::default(),
+            presence: PresenceSettings::default(),
+            storage: StorageConfig::default(),
+            auth: AuthConfig::default(),
+            shutdown: ShutdownConfig::default(),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            static_dir: None,
+            websocket_compression: true,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            outbound_queue: 256,
+            write_timeout: 10,
+            outbound_overflow: OverflowPolicy::DropPresence,
+            overload_sessions: None,
+            resume_window: 30,
+        }
+    }
+}
+
+impl Default for PresenceSettings {
+    fn default() -> Self {
+        Self {
+            ttl: doc_manager::DEFAULT_PRESENCE_TTL.as_secs(),
+            interval: doc_manager::DEFAULT_PRESENCE_INTERVAL.as_secs(),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            pre_drain: 5,
+            timeout: 30,
+            reconnect_hint: 5,
+        }
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(s: &str) -> ListenerConfig {
+        s.parse().unwrap()
+    }
+
+    fn listener_error(s: &str) -> String {
+        s.parse::<ListenerConfig>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_every_listener_scheme() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 49243));
+        for (scheme, protocol, tls) in [
+            ("shrub", ListenerProtocol::Shrub, false),
+            ("shrubs", ListenerProtocol::Shrub, true),
+            ("http", ListenerProtocol::Http, false),
+            ("https", ListenerProtocol::Http, true),
+            ("unified", ListenerProtocol::Unified, false),
+            ("metrics", ListenerProtocol::Metrics, false),
+        ] {
+            let s = format!("{}://127.0.0.1:49243", scheme);
+            assert_eq!(
+                listener(&s),
+                ListenerConfig::new(protocol, address, tls),
+                "{}",
+                s
+            );
+            // Displays as it's written on the command line
+            assert_eq!(listener(&s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn parses_ipv6_listener_addresses() {
+        let config = listener("shrubs://[::]:49244");
+        assert_eq!(config.address, "[::]:49244".parse().unwrap());
+        assert!(config.tls);
+        assert!(!config.ipv6_only);
+        assert_eq!(config.to_string(), "shrubs://[::]:49244");
+
+        let config = listener("http://[::1]:0");
+        assert_eq!(config.address, "[::1]:0".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_listeners() {
+        assert_eq!(
+            listener_error("ftp://127.0.0.1:21"),
+            "unknown listener scheme ftp"
+        );
+        assert_eq!(
+            listener_error("HTTP://127.0.0.1:80"),
+            "unknown listener scheme HTTP"
+        );
+        assert_eq!(
+            listener_error("127.0.0.1:8080"),
+            "expected <scheme>://<address>, got 127.0.0.1:8080"
+        );
+        assert_eq!(
+            listener_error("http://localhost:8080"),
+            "invalid listener address localhost:8080"
+        );
+        // IPv6 addresses need brackets to tell the port apart
+        assert_eq!(
+            listener_error("http://::1:8080"),
+            "invalid listener address ::1:8080"
+        );
+        assert_eq!(
+            listener_error("http://127.0.0.1"),
+            "invalid listener address 127.0.0.1"
+        );
+    }
+
+    #[test]
+    fn rejects_tls_on_unified_and_metrics_listeners() {
+        let address = SocketAddr::from(([0, 0, 0, 0], 443));
+        let unified = ListenerConfig::new(ListenerProtocol::Unified, address, true);
+        assert_eq!(
+            unified.validate().unwrap_err().to_string(),
+            "unified listener 0.0.0.0:443 detects TLS itself, so tls can't be set"
+        );
+        let metrics = ListenerConfig::new(ListenerProtocol::Metrics, address, true);
+        assert_eq!(
+            metrics.validate().unwrap_err().to_string(),
+            "metrics listener 0.0.0.0:443 doesn't support TLS"
+        );
+
+        for s in [
+            "shrubs://0.0.0.0:1",
+            "https://0.0.0.0:1",
+            "unified://0.0.0.0:1",
+        ] {
+            listener(s).validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_listeners_from_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            [[listeners]]
+            protocol = "unified"
+            address = "[::]:443"
+            ipv6_only = true
+
+            [[listeners]]
+            protocol = "metrics"
+            address = "127.0.0.1:9090"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[0].to_string(), "unified://[::]:443");
+        assert!(config.listeners[0].ipv6_only);
+        assert!(!config.listeners[1].tls);
+
+        let err = toml::from_str::<Config>(
+            r#"
+            [[listeners]]
+            protocol = "gopher"
+            address = "[::]:70"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("gopher"), "{}", err);
+    }
+
+    /// The error from validating the defaults with `toml` applied on top.
+    fn validation_error(toml: &str) -> String {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        Config::default().validate().unwrap();
+        let config: Config = toml::from_str("").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.listeners, Config::default().listeners);
+    }
+
+    #[test]
+    fn fills_in_missing_settings() {
+        let config: Config = toml::from_str(
+            r#"
+            [limits]
+            outbound_queue = 16
+
+            [presence]
+            ttl = 60
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.limits.outbound_queue, 16);
+        assert_eq!(config.limits.write_timeout, 10);
+        assert_eq!(config.presence.ttl, 60);
+        assert_eq!(
+            config.presence.interval,
+            doc_manager::DEFAULT_PRESENCE_INTERVAL.as_secs()
+        );
+        assert!(config.http.websocket_compression);
+    }
+
+    #[test]
+    fn rejects_bad_timings_and_limits() {
+        assert_eq!(
+            validation_error("presence = { interval = 0 }"),
+            "presence.interval must be at least 1"
+        );
+        assert_eq!(
+            validation_error("presence = { ttl = 0, interval = 1 }"),
+            "presence.ttl must be at least 1"
+        );
+        assert_eq!(
+            validation_error("presence = { ttl = 5, interval = 10 }"),
+            "presence.ttl (5) must be at least presence.interval (10)"
+        );
+        assert_eq!(
+            validation_error("limits = { outbound_queue = 0 }"),
+            "limits.outbound_queue must be at least 1"
+        );
+
+        let config: Config = toml::from_str("presence = { ttl = 10, interval = 10 }").unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        for toml in [
+            "data_dri = \"/var/lib/shrub\"",
+            "[limits]\noutbound_queu = 16",
+            "[presence]\nintervall = 10",
+            "[shutdown]\npredrain = 1",
+            "[[listeners]]\nprotocol = \"http\"\naddress = \"127.0.0.1:80\"\ntsl = true",
+        ] {
+            let err = toml::from_str::<Config>(toml).unwrap_err();
+            assert!(
+                err.to_string().contains("unknown field"),
+                "{}: {}",
+                toml,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn parses_log_formats() {
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert!("JSON".parse::<LogFormat>().is_err());
+        let config: Config = toml::from_str("log_format = \"json\"").unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let config: Config = toml::from_str(
+            r#"
+            [auth]
+            metrics_token = "hunter2"
+
+            [tls]
+            identity = "server.pfx"
+            identity_password = "swordfish"
+            "#,
+        )
+        .unwrap();
+        let toml = config.to_redacted_toml();
+        assert!(!toml.contains("hunter2"), "{}", toml);
+        assert!(!toml.contains("swordfish"), "{}", toml);
+        assert!(toml.contains("metrics_token = \"<redacted>\""), "{}", toml);
+        assert_eq!(
+            config.auth.metrics_token().unwrap().unwrap().expose(),
+            "hunter2"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use crate::proto::outbound::QueueStats;
+use shrubbery_common::frame::ConnectionInfo;
+use shrubbery_common::DocId;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{field, info_span, Span};
+
+/// The authenticated frame connections currently open.
+#[derive(Clone, Debug, Default)]
+pub struct Connections(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    live: BTreeMap<u64, Live>,
+}
+
+#[derive(Debug)]
+struct Live {
+    user: String,
+    origin: Origin,
+    open_docs: HashSet<DocId>,
+    connected_at: u64,
+    queue: Arc<QueueStats>,
+    kick_tx: mpsc::UnboundedSender<Kick>,
+}
+
+/// How a connection reached the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Shrub,
+    ShrubTls,
+    Http,
+    Https,
+    /// Frames over a websocket upgraded from HTTP
+    Websocket,
+    /// Frames over a websocket upgraded from HTTPS
+    WebsocketTls,
+    Admin,
+}
+
+/// Where a connection came from.
+#[derive(Debug, Clone, Copy)]
+pub struct Origin {
+    /// Allocated by [`Connections::next_id`] when the connection was accepted
+    pub id: u64,
+    pub transport: Transport,
+    /// `None` for connections that didn't come over the network, such as the admin socket
+    pub peer: Option<SocketAddr>,
+}
+
+/// What an admin asked a connection to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kick {
+    /// Close the connection and forget its session
+    Connection,
+    /// Close the doc
+    Doc(DocId),
+}
+
+/// Removes the connection from the registry when dropped.
+#[derive(Debug)]
+pub struct Registration {
+    id: u64,
+    connections: Connections,
+    kick_rx: mpsc::UnboundedReceiver<Kick>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocates the id of a connection that was just accepted. Only connections that
+    /// authenticate are registered, so the ids listed have gaps.
+    pub fn next_id(&self) -> u64 {
+        let mut inner = self.0.lock().unwrap();
+        inner.next_id += 1;
+        inner.next_id
+    }
+
+    /// Adds a connection that has `open_docs` open, such as a resumed session, under the id in
+    /// its origin.
+    pub fn register(
+        &self,
+        user: String,
+        origin: Origin,
+        open_docs: impl IntoIterator<Item = DocId>,
+        queue: Arc<QueueStats>,
+    ) -> Registration {
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let (kick_tx, kick_rx) = mpsc::unbounded_channel();
+        let id = origin.id;
+        self.0.lock().unwrap().live.insert(
+            id,
+            Live {
+                user,
+                origin,
+                open_docs: open_docs.into_iter().collect(),
+                connected_at,
+                queue,
+                kick_tx,
+            },
+        );
+        Registration {
+            id,
+            connections: self.clone(),
+            kick_rx,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().live.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .live
+            .iter()
+            .map(|(&id, live)| ConnectionInfo {
+                id,
+                user: live.user.clone(),
+                transport: live.origin.transport.label().to_string(),
+                peer: live.origin.peer.map(|peer| peer.to_string()),
+                open_docs: live.open_docs.iter().copied().collect(),
+                connected_at: live.connected_at,
+                queue: live.queue.snapshot(),
+            })
+            .collect()
+    }
+
+    /// Passes `kick` on to connection `id`, returning false if there's no such connection or it
+    /// doesn't have the doc open.
+    pub fn kick(&self, id: u64, kick: Kick) -> bool {
+        let inner = self.0.lock().unwrap();
+        let Some(live) = inner.live.get(&id) else {
+            return false;
+        };
+        if let Kick::Doc(doc) = kick {
+            if !live.open_docs.contains(&doc) {
+                return false;
+            }
+        }
+        live.kick_tx.send(kick).is_ok()
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Live)) {
+        if let Some(live) = self.0.lock().unwrap().live.get_mut(&id) {
+            f(live);
+        }
+    }
+}
+
+impl Transport {
+    pub const ALL: [Transport; 7] = [
+        Transport::Shrub,
+        Transport::ShrubTls,
+        Transport::Http,
+        Transport::Https,
+        Transport::Websocket,
+        Transport::WebsocketTls,
+        Transport::Admin,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Transport::Shrub => "shrub",
+            Transport::ShrubTls => "shrub_tls",
+            Transport::Http => "http",
+            Transport::Https => "https",
+            Transport::Websocket => "websocket",
+            Transport::WebsocketTls => "websocket_tls",
+            Transport::Admin => "admin",
+        }
+    }
+}
+
+impl Origin {
+    pub fn new(id: u64, transport: Transport, peer: Option<SocketAddr>) -> Self {
+        Self {
+            id,
+            transport,
+            peer,
+        }
+    }
+
+    /// The origin of a websocket upgraded from this HTTP connection.
+    pub fn websocket(self) -> Self {
+        let transport = match self.transport {
+            Transport::Http => Transport::Websocket,
+            Transport::Https => Transport::WebsocketTls,
+            other => other,
+        };
+        Self { transport, ..self }
+    }
+}
+
+/// The span everything a connection logs is in, from the moment it's accepted on `listener`.
+/// `transport` is recorded once a frame connection starts, and `user` once it's authenticated.
+pub fn connection_span(id: u64, listener: impl Display, peer: Option<SocketAddr>) -> Span {
+    let span = info_span!(
+        "connection",
+        id,
+        listener = %listener,
+        transport = field::Empty,
+        peer = field::Empty,
+        user = field::Empty,
+    );
+    if let Some(peer) = peer {
+        span.record("peer", field::display(peer));
+    }
+    span
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn opened(&self, doc: DocId) {
+        self.connections.update(self.id, |live| {
+            live.open_docs.insert(doc);
+        });
+    }
+
+    pub fn closed(&self, doc: DocId) {
+        self.connections.update(self.id, |live| {
+            live.open_docs.remove(&doc);
+        });
+    }
+
+    /// Resolves when an admin kicks the connection.
+    pub async fn kicked(&mut self) -> Kick {
+        match self.kick_rx.recv().await {
+            Some(kick) => kick,
+            // The sender lives in the registry until this is dropped
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.0.lock().unwrap().live.remove(&self.id);
+    }
+}
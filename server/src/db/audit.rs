@@ -0,0 +1,116 @@
+use super::StorageConfig;
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, WriteOptions};
+use shrubbery_common::audit::{self, GENESIS_HASH};
+use shrubbery_common::frame::AuditEntry;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The key readiness probes write. It sorts before every entry, as those are keyed by their
+/// sequence number from 1, so neither queries nor finding the last entry see it.
+const PROBE_KEY: &[u8] = &[0];
+
+/// Administrative actions, keyed by their sequence number and chained by hash.
+#[derive(Clone, Debug)]
+pub struct AuditDb(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    db: DBWithThreadMode<MultiThreaded>,
+    /// The sequence number and hash of the last entry, held while appending so entries are
+    /// written one at a time
+    last: Mutex<(u64, String)>,
+}
+
+/// An action to record. The actor is the authenticated user.
+#[derive(Debug)]
+pub struct AuditEvent {
+    pub actor: String,
+    pub action: &'static str,
+    pub target: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub details: Option<serde_json::Value>,
+}
+
+impl AuditDb {
+    pub fn open(path: impl Into<PathBuf>, storage: &StorageConfig) -> eyre::Result<Self> {
+        let path = path.into();
+        let opts = storage.options();
+        let db = DBWithThreadMode::<MultiThreaded>::open(&opts, path.clone())?;
+        let last = match db.iterator(IteratorMode::End).next() {
+            Some(res) => {
+                let (_, value) = res?;
+                let entry: AuditEntry = serde_json::from_slice(&value)?;
+                (entry.seq, entry.hash)
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        Ok(Self(Arc::new(Inner {
+            db,
+            last: Mutex::new(last),
+        })))
+    }
+
+    /// Writes `event` to disk as the next entry before returning it. The synced write runs on a
+    /// blocking thread, as it waits for the disk.
+    pub async fn append(&self, event: AuditEvent) -> eyre::Result<AuditEntry> {
+        let inner = self.0.clone();
+        tokio::task::spawn_blocking(move || inner.append(event)).await?
+    }
+
+    /// Up to `limit` entries in order, starting after the entry numbered `after`.
+    pub fn query(&self, after: u64, limit: usize) -> eyre::Result<Vec<AuditEntry>> {
+        let start = after.saturating_add(1).to_be_bytes();
+        self.0
+            .db
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+            .take(limit)
+            .map(|res| {
+                let (_, value) = res?;
+                Ok(serde_json::from_slice(&value)?)
+            })
+            .collect()
+    }
+
+    pub fn flush(&self) -> eyre::Result<()> {
+        self.0.db.flush()?;
+        Ok(())
+    }
+
+    /// Writes and reads back a key, failing if the database can't currently be written.
+    pub fn probe(&self) -> eyre::Result<()> {
+        super::probe(PROBE_KEY, |k, v| self.0.db.put(k, v), |k| self.0.db.get(k))
+    }
+
+    /// Reads an integer RocksDB property such as `rocksdb.estimate-num-keys`.
+    pub fn property(&self, name: &str) -> eyre::Result<Option<u64>> {
+        Ok(self.0.db.property_int_value(name)?)
+    }
+}
+
+impl Inner {
+    fn append(&self, event: AuditEvent) -> eyre::Result<AuditEntry> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut last = self.last.lock().unwrap();
+        let mut entry = AuditEntry {
+            seq: last.0 + 1,
+            timestamp,
+            actor: event.actor,
+            action: event.action.to_string(),
+            target: event.target,
+            peer: event.peer.map(|peer| peer.to_string()),
+            details: event.details,
+            prev_hash: last.1.clone(),
+            hash: String::new(),
+        };
+        entry.hash = audit::entry_hash(&entry);
+
+        let mut opts = WriteOptions::default();
+        opts.set_sync(true);
+        self.db
+            .put_opt(entry.seq.to_be_bytes(), serde_json::to_vec(&entry)?, &opts)?;
+        *last = (entry.seq, entry.hash.clone());
+        Ok(entry)
+    }
+}
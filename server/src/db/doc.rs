@@ -1,6 +1,11 @@
+use super::StorageConfig;
 use rocksdb::{DBWithThreadMode, MultiThreaded};
+use serde::{Deserialize, Serialize};
+use shrubbery_common::frame::DocInfo;
+use shrubbery_common::DocId;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use ulid::Ulid;
 
 #[derive(Clone, Debug)]
@@ -11,18 +16,70 @@ struct Inner {
     db: DBWithThreadMode<MultiThreaded>,
 }
 
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct StoredDoc {
+    created_by: Option<String>,
+    created_at: Option<u64>,
+}
+
 impl DocDb {
-    pub fn open(path: impl Into<PathBuf>) -> eyre::Result<Self> {
+    pub fn open(path: impl Into<PathBuf>, storage: &StorageConfig) -> eyre::Result<Self> {
         let path = path.into();
-        let mut opts = rocksdb::Options::default();
-        opts.create_if_missing(true);
+        let opts = storage.options();
         let db = DBWithThreadMode::<MultiThreaded>::open(&opts, path.clone())?;
         Ok(Self(Arc::new(Inner { db })))
     }
 
-    pub fn create(&self) -> eyre::Result<u128> {
-        let id = Ulid::new().0;
-        self.0.db.put(id.to_be_bytes(), b"")?;
-        Ok(id)
+    pub fn create(&self, created_by: &str) -> eyre::Result<DocInfo> {
+        let doc = DocId(Ulid::new().0);
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let stored = StoredDoc {
+            created_by: Some(created_by.to_string()),
+            created_at: Some(created_at),
+        };
+        self.0
+            .db
+            .put(doc.0.to_be_bytes(), serde_json::to_vec(&stored)?)?;
+        Ok(to_info(doc, stored))
+    }
+
+    pub fn get(&self, doc: DocId) -> eyre::Result<Option<DocInfo>> {
+        let Some(bytes) = self.0.db.get(doc.0.to_be_bytes())? else {
+            return Ok(None);
+        };
+        // Docs created before metadata was stored have an empty value
+        let stored = if bytes.is_empty() {
+            StoredDoc::default()
+        } else {
+            serde_json::from_slice(&bytes)?
+        };
+        Ok(Some(to_info(doc, stored)))
+    }
+
+    pub fn flush(&self) -> eyre::Result<()> {
+        self.0.db.flush()?;
+        Ok(())
+    }
+
+    /// Writes and reads back a key, failing if the database can't currently be written.
+    pub fn probe(&self) -> eyre::Result<()> {
+        super::probe(
+            super::PROBE_KEY,
+            |k, v| self.0.db.put(k, v),
+            |k| self.0.db.get(k),
+        )
+    }
+
+    /// Reads an integer RocksDB property such as `rocksdb.estimate-num-keys`.
+    pub fn property(&self, name: &str) -> eyre::Result<Option<u64>> {
+        Ok(self.0.db.property_int_value(name)?)
+    }
+}
+
+fn to_info(doc: DocId, stored: StoredDoc) -> DocInfo {
+    DocInfo {
+        doc,
+        created_by: stored.created_by,
+        created_at: stored.created_at,
     }
 }
@@ -1,5 +1,66 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod audit;
 mod doc;
 mod user;
 
+pub use audit::{AuditDb, AuditEvent};
 pub use doc::DocDb;
 pub use user::UserDb;
+
+/// The key readiness probes write to the users and docs databases. Doc ids are 16 bytes and user
+/// keys contain a colon, so it can't clash with either.
+const PROBE_KEY: &[u8] = b"readiness-probe";
+
+/// RocksDB tuning shared by both databases. Options left unset keep RocksDB's defaults.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Files each database may keep open, -1 for no limit
+    pub max_open_files: Option<i32>,
+    /// Bytes a memtable may grow to before it's flushed
+    pub write_buffer_size: Option<usize>,
+    /// Memtables that may be kept in memory, including those being flushed
+    pub max_write_buffer_number: Option<i32>,
+    /// Background threads for flushes and compactions
+    pub parallelism: Option<i32>,
+}
+
+impl StorageConfig {
+    fn options(&self) -> rocksdb::Options {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        if let Some(files) = self.max_open_files {
+            opts.set_max_open_files(files);
+        }
+        if let Some(size) = self.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(number) = self.max_write_buffer_number {
+            opts.set_max_write_buffer_number(number);
+        }
+        if let Some(threads) = self.parallelism {
+            opts.increase_parallelism(threads);
+        }
+        opts
+    }
+}
+
+/// Writes a fresh value to `key` and reads it back, failing if the database can't currently be
+/// written, such as after a background error or with the disk full.
+fn probe(
+    key: &[u8],
+    put: impl FnOnce(&[u8], &[u8]) -> Result<(), rocksdb::Error>,
+    get: impl FnOnce(&[u8]) -> Result<Option<Vec<u8>>, rocksdb::Error>,
+) -> eyre::Result<()> {
+    let value = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_nanos()
+        .to_be_bytes();
+    put(key, &value)?;
+    if get(key)?.as_deref() != Some(&value[..]) {
+        eyre::bail!("read back a different value than was written");
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use super::StorageConfig;
 use rocksdb::{MultiThreaded, OptimisticTransactionDB};
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -12,10 +13,9 @@ struct Inner {
 }
 
 impl UserDb {
-    pub fn open(path: impl Into<PathBuf>) -> eyre::Result<Self> {
+    pub fn open(path: impl Into<PathBuf>, storage: &StorageConfig) -> eyre::Result<Self> {
         let path = path.into();
-        let mut opts = rocksdb::Options::default();
-        opts.create_if_missing(true);
+        let opts = storage.options();
         let db = OptimisticTransactionDB::<MultiThreaded>::open(&opts, path.clone())?;
         Ok(Self(Arc::new(Inner { db })))
     }
@@ -36,4 +36,23 @@ impl UserDb {
             Ok(id)
         }
     }
+
+    pub fn flush(&self) -> eyre::Result<()> {
+        self.0.db.flush()?;
+        Ok(())
+    }
+
+    /// Writes and reads back a key, failing if the database can't currently be written.
+    pub fn probe(&self) -> eyre::Result<()> {
+        super::probe(
+            super::PROBE_KEY,
+            |k, v| self.0.db.put(k, v),
+            |k| self.0.db.get(k),
+        )
+    }
+
+    /// Reads an integer RocksDB property such as `rocksdb.estimate-num-keys`.
+    pub fn property(&self, name: &str) -> eyre::Result<Option<u64>> {
+        Ok(self.0.db.property_int_value(name)?)
+    }
 }
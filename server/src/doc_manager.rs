@@ -1,21 +1,47 @@
 use crate::db::DocDb;
-use shrubbery_common::frame::PresenceFrame;
+use crate::metrics::{Metrics, PresenceDrop};
+use crate::shutdown::Shutdown;
+use shrubbery_common::frame::{OpenDocInfo, PresenceFrame};
 use shrubbery_common::DocId;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::select;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::interval;
-use tracing::trace;
+use tracing::{info_span, trace, warn, Instrument};
+
+pub const DEFAULT_PRESENCE_TTL: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_PRESENCE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct PresenceConfig {
+    /// How long presence is kept after its last update.
+    pub ttl: Duration,
+    /// How often everyone's presence in a doc is rebroadcast.
+    pub interval: Duration,
+}
 
 #[derive(Debug, Clone)]
 pub struct DocManager {
     db: DocDb,
     opens: OpenMap,
+    presence: PresenceConfig,
+    shutdown: Shutdown,
+    metrics: Metrics,
 }
 
-type OpenMap = Arc<Mutex<HashMap<DocId, mpsc::Sender<OpenRequest>>>>;
+type OpenMap = Arc<Mutex<HashMap<DocId, DocEntry>>>;
+
+/// A running doc worker.
+#[derive(Debug, Clone)]
+struct DocEntry {
+    open_tx: mpsc::Sender<OpenRequest>,
+    /// Connections with the doc open, kept up to date by the worker
+    clients: Arc<AtomicUsize>,
+}
 
 struct OpenRequest {
     user: String,
@@ -32,12 +58,32 @@ pub struct DocHandle {
     user: String,
     user_info: Option<serde_json::Value>,
     presence_tx: mpsc::Sender<(Instant, PresenceFrame)>,
+    /// Tells the worker the handle was dropped by closing when it is
+    _alive: oneshot::Receiver<()>,
 }
 
 impl DocManager {
-    pub fn new(db: DocDb) -> Self {
+    pub fn new(db: DocDb, presence: PresenceConfig, shutdown: Shutdown, metrics: Metrics) -> Self {
         let opens = OpenMap::default();
-        Self { db, opens }
+        Self {
+            db,
+            opens,
+            presence,
+            shutdown,
+            metrics,
+        }
+    }
+
+    /// The docs with a running worker and how many connections have each open.
+    pub fn open_docs(&self) -> Vec<OpenDocInfo> {
+        let map = self.opens.lock().unwrap();
+        map.iter()
+            .filter(|(_, entry)| !entry.open_tx.is_closed())
+            .map(|(&doc, entry)| OpenDocInfo {
+                doc,
+                clients: entry.clients.load(Ordering::Relaxed),
+            })
+            .collect()
     }
 
     pub async fn open(
@@ -50,7 +96,7 @@ impl DocManager {
         loop {
             let tx = {
                 let mut map = self.opens.lock().unwrap();
-                let mut maybe_tx = map.get(&doc).cloned();
+                let mut maybe_tx = map.get(&doc).map(|entry| entry.open_tx.clone());
                 if let Some(sender) = maybe_tx.as_ref() {
                     if sender.is_closed() {
                         map.remove(&doc);
@@ -61,11 +107,23 @@ impl DocManager {
                     Some(tx) => tx,
                     None => {
                         let (tx, rx) = mpsc::channel(1);
+                        let clients = Arc::new(AtomicUsize::new(0));
                         let db = self.db.clone();
-                        tokio::spawn(async move {
-                            doc_worker(doc, db, rx).await;
-                        });
-                        map.insert(doc, tx.clone());
+                        let worker_clients = clients.clone();
+                        let presence = self.presence;
+                        let metrics = self.metrics.clone();
+                        let shutdown = self.shutdown.clone();
+                        self.shutdown.spawn(
+                            doc_worker(doc, db, presence, worker_clients, metrics, rx, shutdown)
+                                .instrument(info_span!("doc", %doc)),
+                        );
+                        map.insert(
+                            doc,
+                            DocEntry {
+                                open_tx: tx.clone(),
+                                clients,
+                            },
+                        );
                         tx
                     }
                 }
@@ -116,33 +174,50 @@ impl DocHandle {
 //   changes. On the other hand this might require whole-protocol changes. So for now just kick and
 //   once we have a working test case we can think about again.
 
-async fn doc_worker(doc: DocId, db: DocDb, mut open_rx: mpsc::Receiver<OpenRequest>) {
+async fn doc_worker(
+    doc: DocId,
+    db: DocDb,
+    config: PresenceConfig,
+    clients: Arc<AtomicUsize>,
+    metrics: Metrics,
+    mut open_rx: mpsc::Receiver<OpenRequest>,
+    shutdown: Shutdown,
+) {
     trace!("creating doc worker for {}", doc);
     let mut next_handle_id = 1;
     let mut client_map: HashMap<u32, ClientEntry> = HashMap::new();
     let mut presence_map: HashMap<u32, (Instant, PresenceFrame)> = HashMap::new();
     let (presence_tx, mut presence_rx) = mpsc::channel(1);
-    let mut presence_interval = interval(Duration::from_secs(10));
+    let mut presence_interval = interval(config.interval);
     loop {
         select! {
+            _ = shutdown.triggered() => {
+                trace!("shutting down doc worker for {}", doc);
+                break;
+            }
+
             req = open_rx.recv() => {
                 let req = match req {
                     Some(tx) => tx,
                     None => break,
                 };
 
+                let (alive_tx, alive_rx) = oneshot::channel();
                 let handle = DocHandle {
                     doc,
                     id: next_handle_id,
                     user: req.user,
                     user_info: req.user_info,
                     presence_tx: presence_tx.clone(),
+                    _alive: alive_rx,
                 };
                 next_handle_id += 1;
 
                 client_map.insert(handle.id, ClientEntry {
                     presence_tx: req.presence_tx,
+                    alive_tx,
                 });
+                clients.store(client_map.len(), Ordering::Relaxed);
 
                 let _ = req.reply_tx.send(handle);
             }
@@ -152,16 +227,21 @@ async fn doc_worker(doc: DocId, db: DocDb, mut open_rx: mpsc::Receiver<OpenReque
                 presence_map.insert(client, (last_update, frame.clone()));
                 let frame = vec![frame];
                 for (&peer_id, peer) in &client_map {
-                    if peer_id != client {
-                        let _ = peer.presence_tx.try_send(frame.clone());
+                    if peer_id != client && !peer.is_closed() {
+                        peer.send_presence(frame.clone(), &metrics);
                     }
                 }
             }
 
             _ = presence_interval.tick() => {
+                // Connections don't say when they close, so forget them once they have
+                client_map.retain(|_, peer| !peer.is_closed());
+                clients.store(client_map.len(), Ordering::Relaxed);
+
                 let now = Instant::now();
-                presence_map.retain(|_, (last_update, _)| {
-                    now.duration_since(*last_update) < Duration::from_secs(30)
+                presence_map.retain(|client, (last_update, _)| {
+                    client_map.contains_key(client)
+                        && now.duration_since(*last_update) < config.ttl
                 });
 
                 if presence_map.len() > 0 {
@@ -170,18 +250,37 @@ async fn doc_worker(doc: DocId, db: DocDb, mut open_rx: mpsc::Receiver<OpenReque
                         frames.push(frame.clone());
                     }
                     for (_, peer) in &client_map {
-                        let _ = peer.presence_tx.try_send(frames.clone());
+                        peer.send_presence(frames.clone(), &metrics);
                     }
                 }
             }
         }
     }
 
+    if let Err(err) = db.flush() {
+        warn!("Failed to flush doc db for {}: {}", doc, err);
+    }
+
     trace!("closed doc worker for {}", doc);
 }
 
 struct ClientEntry {
     presence_tx: mpsc::Sender<Vec<PresenceFrame>>,
+    /// Closed once the client's handle is dropped, such as when it's kicked from the doc
+    alive_tx: oneshot::Sender<()>,
+}
+
+impl ClientEntry {
+    fn is_closed(&self) -> bool {
+        self.presence_tx.is_closed() || self.alive_tx.is_closed()
+    }
+
+    /// Hands presence to the connection, dropping it if the connection is behind.
+    fn send_presence(&self, frames: Vec<PresenceFrame>, metrics: &Metrics) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.presence_tx.try_send(frames) {
+            metrics.presence_dropped(PresenceDrop::Doc);
+        }
+    }
 }
 
 impl std::fmt::Debug for DocHandle {
@@ -0,0 +1,262 @@
+//! Operations shared by the frame protocol and the REST API.
+
+use crate::connections::{Connections, Kick};
+use crate::db::{AuditDb, AuditEvent, DocDb};
+use crate::doc_manager::DocManager;
+use crate::metrics::{AuthFailure, Metrics};
+use crate::state::authorizer::{self, Authorizer};
+use serde_json::json;
+use shrubbery_common::frame::{AuditEntry, ConnectionInfo, DocInfo, OpenDocInfo, ServerInfo};
+use shrubbery_common::DocId;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// The most audit log entries returned by one query.
+pub const MAX_AUDIT_PAGE: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct Handlers {
+    authorizer: Authorizer,
+    doc_db: DocDb,
+    audit_db: AuditDb,
+    doc_manager: DocManager,
+    connections: Connections,
+    server_info: Arc<ServerInfo>,
+    metrics: Metrics,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    BadRequest(String),
+    Internal(eyre::Report),
+}
+
+impl Handlers {
+    pub fn new(
+        authorizer: Authorizer,
+        doc_db: DocDb,
+        audit_db: AuditDb,
+        doc_manager: DocManager,
+        connections: Connections,
+        server_info: ServerInfo,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            authorizer,
+            doc_db,
+            audit_db,
+            doc_manager,
+            connections,
+            server_info: Arc::new(server_info),
+            metrics,
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The registry connections add themselves to once authenticated.
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    /// What this server supports. Anyone authenticated can see it.
+    pub fn server_info(&self) -> ServerInfo {
+        (*self.server_info).clone()
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<authorizer::Entry, Error> {
+        let entry = self.authorizer.authenticate(token);
+        if entry.is_none() {
+            self.metrics.auth_failed(AuthFailure::Token);
+        }
+        entry.ok_or(Error::Unauthorized)
+    }
+
+    /// Admin actions are recorded in the audit log with `peer`, the address the request came
+    /// from. If recording fails, they fail without taking effect unless noted otherwise.
+    pub async fn mint_token(
+        &self,
+        auth: &authorizer::Entry,
+        peer: Option<SocketAddr>,
+        user: String,
+        info: Option<serde_json::Value>,
+        lifetime_seconds: u64,
+    ) -> Result<String, Error> {
+        require_root(auth)?;
+        info!(
+            "Minting token for user {} with lifetime {}",
+            user, lifetime_seconds
+        );
+        let expiry = Instant::now()
+            .checked_add(Duration::from_secs(lifetime_seconds))
+            .ok_or_else(|| Error::BadRequest("lifetime too long".to_string()))?;
+        self.audit(
+            auth,
+            peer,
+            "mintToken",
+            Some(user.clone()),
+            Some(json!({ "lifetimeSeconds": lifetime_seconds, "info": info })),
+        )
+        .await?;
+        Ok(self
+            .authorizer
+            .mint_token(authorizer::Entry { user, expiry, info }))
+    }
+
+    pub async fn revoke_tokens_for_user(
+        &self,
+        auth: &authorizer::Entry,
+        peer: Option<SocketAddr>,
+        user: String,
+    ) -> Result<(), Error> {
+        require_root(auth)?;
+        if user == "root" {
+            return Err(Error::BadRequest(
+                "cannot use RevokeTokensForUser on root".to_string(),
+            ));
+        }
+        info!("Revoking tokens for user {}", user);
+        self.audit(auth, peer, "revokeTokensForUser", Some(user.clone()), None)
+            .await?;
+        self.authorizer.revoke_tokens_for_user(user);
+        Ok(())
+    }
+
+    pub fn create_doc(&self, auth: &authorizer::Entry) -> Result<DocInfo, Error> {
+        let info = self.doc_db.create(&auth.user)?;
+        info!("Created doc {} for {}", info.doc, auth.user);
+        Ok(info)
+    }
+
+    pub fn get_doc(&self, _auth: &authorizer::Entry, doc: DocId) -> Result<DocInfo, Error> {
+        self.doc_db.get(doc)?.ok_or(Error::NotFound)
+    }
+
+    pub fn list_connections(&self, auth: &authorizer::Entry) -> Result<Vec<ConnectionInfo>, Error> {
+        require_root(auth)?;
+        Ok(self.connections.list())
+    }
+
+    pub fn list_open_docs(&self, auth: &authorizer::Entry) -> Result<Vec<OpenDocInfo>, Error> {
+        require_root(auth)?;
+        Ok(self.doc_manager.open_docs())
+    }
+
+    /// The kick happens before it's recorded, as only then is it known to have succeeded.
+    pub async fn kick_connection(
+        &self,
+        auth: &authorizer::Entry,
+        peer: Option<SocketAddr>,
+        connection: u64,
+    ) -> Result<(), Error> {
+        require_root(auth)?;
+        info!("Kicking connection {}", connection);
+        if !self.connections.kick(connection, Kick::Connection) {
+            return Err(Error::NotFound);
+        }
+        self.audit(
+            auth,
+            peer,
+            "kickConnection",
+            Some(connection.to_string()),
+            None,
+        )
+        .await
+    }
+
+    /// Fails with `NotFound` unless the connection has the doc open. The kick happens before
+    /// it's recorded, as only then is it known to have succeeded.
+    pub async fn kick_from_doc(
+        &self,
+        auth: &authorizer::Entry,
+        peer: Option<SocketAddr>,
+        connection: u64,
+        doc: DocId,
+    ) -> Result<(), Error> {
+        require_root(auth)?;
+        info!("Kicking connection {} from doc {}", connection, doc);
+        if !self.connections.kick(connection, Kick::Doc(doc)) {
+            return Err(Error::NotFound);
+        }
+        self.audit(
+            auth,
+            peer,
+            "kickFromDoc",
+            Some(connection.to_string()),
+            Some(json!({ "doc": doc })),
+        )
+        .await
+    }
+
+    /// Up to `limit` audit log entries after the entry numbered `after`, capped at
+    /// [`MAX_AUDIT_PAGE`]. A limit of 0 is rejected.
+    pub fn query_audit_log(
+        &self,
+        auth: &authorizer::Entry,
+        after: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<AuditEntry>, Error> {
+        require_root(auth)?;
+        if limit == Some(0) {
+            return Err(Error::BadRequest("limit must be at least 1".to_string()));
+        }
+        let limit = limit.unwrap_or(MAX_AUDIT_PAGE).min(MAX_AUDIT_PAGE);
+        Ok(self.audit_db.query(after.unwrap_or(0), limit)?)
+    }
+
+    async fn audit(
+        &self,
+        auth: &authorizer::Entry,
+        peer: Option<SocketAddr>,
+        action: &'static str,
+        target: Option<String>,
+        details: Option<serde_json::Value>,
+    ) -> Result<(), Error> {
+        self.audit_db
+            .append(AuditEvent {
+                actor: auth.user.clone(),
+                action,
+                target,
+                peer,
+                details,
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+fn require_root(auth: &authorizer::Entry) -> Result<(), Error> {
+    if auth.user != "root" {
+        return Err(Error::Forbidden);
+    }
+    Ok(())
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Unauthorized => write!(f, "invalid token"),
+            Error::Forbidden => write!(f, "forbidden"),
+            Error::NotFound => write!(f, "not found"),
+            Error::BadRequest(message) => write!(f, "{}", message),
+            // The details are logged, not shown to clients
+            Error::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<eyre::Report> for Error {
+    fn from(err: eyre::Report) -> Self {
+        Error::Internal(err)
+    }
+}
@@ -1,7 +1,14 @@
+pub mod config;
+pub mod connections;
 pub mod db;
 pub mod doc_manager;
+pub mod handlers;
+pub mod metrics;
 pub mod proto;
+pub mod sessions;
+pub mod shutdown;
 pub mod state;
+pub mod tls;
 
 pub use shrubbery_common::frame::{Frame, FrameType};
 pub use shrubbery_common::framed::FramedConnection;
@@ -1,76 +1,328 @@
-use eyre::eyre;
+use eyre::{eyre, WrapErr};
 use futures::{SinkExt, StreamExt};
-use shrubbery_common::frame::PresenceFrame;
+use shrubbery_common::codec;
+use shrubbery_common::frame::{PresenceFrame, ServerInfo, ServerLimits};
 use shrubbery_common::DocId;
-use shrubbery_server::db::DocDb;
+use shrubbery_server::config::{Config, ListenerConfig, ListenerProtocol, LogFormat, Secret};
+use shrubbery_server::connections::Connections;
 use shrubbery_server::db::UserDb;
+use shrubbery_server::db::{AuditDb, DocDb};
 use shrubbery_server::doc_manager::{DocHandle, DocManager};
+use shrubbery_server::handlers::Handlers;
+use shrubbery_server::metrics::Metrics;
+#[cfg(unix)]
+use shrubbery_server::proto::admin_socket::AdminSocket;
+use shrubbery_server::proto::health::HealthEndpoints;
 use shrubbery_server::proto::http_multiplexer::HttpMultiplexer;
+use shrubbery_server::proto::listener::{Listener, Services};
+use shrubbery_server::proto::metrics_endpoint::MetricsEndpoint;
+use shrubbery_server::proto::outbound::{OutboundConfig, OverflowPolicy};
+use shrubbery_server::proto::sniffer::ProtocolSniffer;
 use shrubbery_server::proto::socket_processor;
 use shrubbery_server::proto::socket_processor::SocketProcessor;
+use shrubbery_server::proto::static_assets::StaticAssets;
+use shrubbery_server::sessions::SessionStore;
+use shrubbery_server::shutdown::Shutdown;
 use shrubbery_server::state::authorizer::{self, Authorizer};
+use shrubbery_server::state::client_certs::ClientCertAuthorizer;
+use shrubbery_server::tls::ReloadableTlsAcceptor;
 use shrubbery_server::{Frame, FrameType, FramedConnection};
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use structopt::StructOpt;
-use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
 use tokio::{fs, select, signal};
-use tokio_native_tls as tokio_tls;
-use tokio_native_tls::TlsStream;
-use tokio_tls::native_tls as tls;
 use tracing::{debug, error, info, trace, warn};
 
 #[derive(Debug, StructOpt)]
 struct Opts {
-    #[structopt(long, short)]
-    data_dir: PathBuf,
+    #[structopt(long, env = "SHRUB_CONFIG")]
+    /// Path to a TOML config file. Flags and environment variables override its settings
+    config: Option<PathBuf>,
 
-    #[structopt(long, short, default_value = "49243")]
-    port: u16,
+    #[structopt(long, short, env = "SHRUB_DATA_DIR")]
+    data_dir: Option<PathBuf>,
 
-    #[structopt(long, default_value = "49244")]
-    secure_port: u16,
+    #[structopt(long = "listen", env = "SHRUB_LISTEN", use_delimiter = true)]
+    /// Accept connections at `<scheme>://<address>`, where the scheme is one of shrub, shrubs,
+    /// http, https, unified (every protocol, told apart by the first bytes each client sends) or
+    /// metrics (only /metrics and the health endpoints). Can be repeated, and replaces the
+    /// listeners in the config file. Port 0 binds any free port [default: shrub://0.0.0.0:49243,
+    /// shrubs://0.0.0.0:49244, http://0.0.0.0:8080, https://0.0.0.0:8443]
+    listen: Vec<ListenerConfig>,
 
-    #[structopt(long, default_value = "80")]
-    websocket_port: u16,
+    #[structopt(long, env = "SHRUB_LISTENERS_FILE")]
+    /// Write the address each listener bound to this file, one per line in the form of --listen
+    listeners_file: Option<PathBuf>,
 
-    #[structopt(long, default_value = "443")]
-    websocket_secure_port: u16,
+    #[structopt(long, env = "SHRUB_LOG_FORMAT")]
+    /// Write logs as text or json. JSON logs include the connection or doc each event is about
+    /// [default: text]
+    log_format: Option<LogFormat>,
 
-    #[structopt(long)]
-    /// Path to a PKCS12 file containing the TLS identity to use for the server
+    #[structopt(long, env = "SHRUB_TLS_IDENTITY")]
+    /// Path to a PKCS12 file containing the TLS identity to use for the server. The identity is
+    /// reloaded when the server receives SIGHUP
     tls_identity: Option<PathBuf>,
 
-    #[structopt(long)]
-    /// Password for the PKCS12 file containing the TLS identity to use for the server
+    #[structopt(long, env = "SHRUB_TLS_IDENTITY_PASSWORD", hide_env_values = true)]
+    /// Password for the PKCS12 file containing the TLS identity to use for the server. Prefer
+    /// --tls-identity-password-file, as command lines can be seen by other users
     tls_identity_password: Option<String>,
+
+    #[structopt(
+        long,
+        env = "SHRUB_TLS_IDENTITY_PASSWORD_FILE",
+        conflicts_with = "tls-identity-password"
+    )]
+    /// Path to a file containing the password for --tls-identity
+    tls_identity_password_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        env = "SHRUB_TLS_CERT",
+        conflicts_with = "tls-identity",
+        requires = "tls-key"
+    )]
+    /// Path to a PEM file containing the TLS certificate chain to use for the server. Requires the
+    /// rustls feature. The certificate and key are reloaded when the server receives SIGHUP
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_TLS_KEY", requires = "tls-cert")]
+    /// Path to a PEM file containing the private key for --tls-cert
+    tls_key: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_TLS_CLIENT_CA")]
+    /// Path to a PEM file containing the CA certificates that sign client certificates. Clients
+    /// presenting a certificate signed by one of them don't need to send a token. Requires
+    /// --tls-cert and --tls-client-users
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_TLS_CLIENT_USERS")]
+    /// Path to a JSON file mapping client certificate common names to users, in the form
+    /// `{"<common name>": {"user": "<user>", "info": <user info>}}`
+    tls_client_users: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_STATIC_DIR")]
+    /// Serve static files over HTTP from this directory instead of the embedded bundle
+    static_dir: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_NO_WEBSOCKET_COMPRESSION")]
+    /// Disable permessage-deflate compression for websocket connections. The environment variable
+    /// takes true or false
+    no_websocket_compression: Option<Option<bool>>,
+
+    #[structopt(long, env = "SHRUB_ADMIN_SOCKET")]
+    /// Listen on a Unix domain socket at this path for local administration. Connections from the
+    /// same user as the server are treated as root without needing a token
+    admin_socket: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_RESUME_WINDOW")]
+    /// Seconds after a connection drops during which the client can resume its session. 0
+    /// disables resumption [default: 30]
+    resume_window: Option<u64>,
+
+    #[structopt(long, env = "SHRUB_OUTBOUND_QUEUE")]
+    /// Frames that may be waiting to be written to a connection before its queue overflows, at
+    /// least 1 [default: 256]
+    outbound_queue: Option<usize>,
+
+    #[structopt(long, env = "SHRUB_WRITE_TIMEOUT")]
+    /// Seconds writing a frame to a connection may take before the connection is closed
+    /// [default: 10]
+    write_timeout: Option<u64>,
+
+    #[structopt(long, env = "SHRUB_OUTBOUND_OVERFLOW")]
+    /// What to do when a connection's outbound queue overflows. With drop-presence, presence
+    /// frames are dropped and the connection is closed if a reply doesn't fit. With disconnect,
+    /// the connection is closed as soon as anything doesn't fit [default: drop-presence]
+    outbound_overflow: Option<OverflowPolicy>,
+
+    #[structopt(long, env = "SHRUB_OVERLOAD_SESSIONS")]
+    /// Report not ready at /readyz once this many sessions are authenticated, so load balancers
+    /// send new clients elsewhere
+    overload_sessions: Option<usize>,
+
+    #[structopt(long, env = "SHRUB_PRESENCE_TTL")]
+    /// Seconds presence is kept after its last update, at least --presence-interval [default: 30]
+    presence_ttl: Option<u64>,
+
+    #[structopt(long, env = "SHRUB_PRESENCE_INTERVAL")]
+    /// Seconds between rebroadcasts of everyone's presence in a doc, at least 1 [default: 10]
+    presence_interval: Option<u64>,
+
+    #[structopt(long, env = "SHRUB_DB_MAX_OPEN_FILES")]
+    /// Files each database may keep open, -1 for no limit
+    db_max_open_files: Option<i32>,
+
+    #[structopt(long, env = "SHRUB_DB_WRITE_BUFFER_SIZE")]
+    /// Bytes a database memtable may grow to before it's flushed
+    db_write_buffer_size: Option<usize>,
+
+    #[structopt(long, env = "SHRUB_DB_MAX_WRITE_BUFFER_NUMBER")]
+    /// Memtables each database may keep in memory
+    db_max_write_buffer_number: Option<i32>,
+
+    #[structopt(long, env = "SHRUB_DB_PARALLELISM")]
+    /// Background threads for database flushes and compactions
+    db_parallelism: Option<i32>,
+
+    #[structopt(long, env = "SHRUB_ROOT_TOKEN_FILE")]
+    /// Path to the file holding the root token, which is generated if missing. Defaults to
+    /// `root_token` in the data directory
+    root_token_file: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_METRICS_TOKEN", hide_env_values = true)]
+    /// Require `Authorization: Bearer <token>` for /metrics. Unless there's a metrics listener,
+    /// metrics are only served on the HTTP listeners when this is set
+    metrics_token: Option<String>,
+
+    #[structopt(
+        long,
+        env = "SHRUB_METRICS_TOKEN_FILE",
+        conflicts_with = "metrics-token"
+    )]
+    /// Path to a file containing the token for --metrics-token
+    metrics_token_file: Option<PathBuf>,
+
+    #[structopt(long, env = "SHRUB_SHUTDOWN_PRE_DRAIN")]
+    /// Seconds /readyz fails after a shutdown signal before connections are closed [default: 5]
+    shutdown_pre_drain: Option<u64>,
+
+    #[structopt(long, env = "SHRUB_SHUTDOWN_TIMEOUT")]
+    /// Seconds to wait for connections and doc workers to finish when shutting down [default: 30]
+    shutdown_timeout: Option<u64>,
+
+    #[structopt(long, env = "SHRUB_SHUTDOWN_RECONNECT_HINT")]
+    /// Seconds clients are told to wait before reconnecting after a shutdown [default: 5]
+    shutdown_reconnect_hint: Option<u64>,
 }
 
-const SELF_SIGNED_IDENTITY: &[u8] = include_bytes!("../self_signed.pfx");
+impl Opts {
+    /// Reads the config file if there is one and applies the flags set over it.
+    fn into_config(self) -> eyre::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        set_some(&mut config.data_dir, self.data_dir);
+
+        if !self.listen.is_empty() {
+            config.listeners = self.listen;
+        }
+        set_some(&mut config.listeners_file, self.listeners_file);
+        set(&mut config.log_format, self.log_format);
+        set_some(&mut config.admin_socket, self.admin_socket);
+
+        let tls = &mut config.tls;
+        set_some(&mut tls.identity, self.tls_identity);
+        // A secret given one way replaces one given the other way in the file
+        if let Some(password) = self.tls_identity_password {
+            tls.identity_password = Some(Secret::new(password));
+            tls.identity_password_file = None;
+        }
+        if let Some(path) = self.tls_identity_password_file {
+            tls.identity_password_file = Some(path);
+            tls.identity_password = None;
+        }
+        set_some(&mut tls.cert, self.tls_cert);
+        set_some(&mut tls.key, self.tls_key);
+        set_some(&mut tls.client_ca, self.tls_client_ca);
+        set_some(&mut tls.client_users, self.tls_client_users);
 
-const CORE_WASM: &[u8] = b"TODO"; // TODO:
+        set_some(&mut config.http.static_dir, self.static_dir);
+        // Given without a value, the flag means true
+        if let Some(disabled) = self.no_websocket_compression {
+            config.http.websocket_compression = !disabled.unwrap_or(true);
+        }
+
+        let limits = &mut config.limits;
+        set(&mut limits.resume_window, self.resume_window);
+        set(&mut limits.outbound_queue, self.outbound_queue);
+        set(&mut limits.write_timeout, self.write_timeout);
+        set(&mut limits.outbound_overflow, self.outbound_overflow);
+        set_some(&mut limits.overload_sessions, self.overload_sessions);
+
+        set(&mut config.presence.ttl, self.presence_ttl);
+        set(&mut config.presence.interval, self.presence_interval);
+
+        let storage = &mut config.storage;
+        set_some(&mut storage.max_open_files, self.db_max_open_files);
+        set_some(&mut storage.write_buffer_size, self.db_write_buffer_size);
+        set_some(
+            &mut storage.max_write_buffer_number,
+            self.db_max_write_buffer_number,
+        );
+        set_some(&mut storage.parallelism, self.db_parallelism);
+
+        let auth = &mut config.auth;
+        set_some(&mut auth.root_token_file, self.root_token_file);
+        if let Some(token) = self.metrics_token {
+            auth.metrics_token = Some(Secret::new(token));
+            auth.metrics_token_file = None;
+        }
+        if let Some(path) = self.metrics_token_file {
+            auth.metrics_token_file = Some(path);
+            auth.metrics_token = None;
+        }
+
+        set(&mut config.shutdown.pre_drain, self.shutdown_pre_drain);
+        set(&mut config.shutdown.timeout, self.shutdown_timeout);
+        set(
+            &mut config.shutdown.reconnect_hint,
+            self.shutdown_reconnect_hint,
+        );
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn set<T>(setting: &mut T, flag: Option<T>) {
+    if let Some(value) = flag {
+        *setting = value;
+    }
+}
+
+fn set_some<T>(setting: &mut Option<T>, flag: Option<T>) {
+    if flag.is_some() {
+        *setting = flag;
+    }
+}
+
+/// The build of `shrubbery-core` for browsers. See `build.rs`.
+const CORE_WASM: Option<&[u8]> = include!(concat!(env!("OUT_DIR"), "/core_wasm.rs"));
+
+/// Files embedded at build time from the directory in `SHRUB_STATIC_BUNDLE`, if set.
+const STATIC_BUNDLE: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/static_bundle.rs"));
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_writer(std::io::stderr)
-        .init();
-
-    let opts = Opts::from_args();
+    let config = Opts::from_args().into_config()?;
+    init_logging(config.log_format);
+    info!(
+        "Effective config:\n{}",
+        config.to_redacted_toml().trim_end()
+    );
 
-    info!("Using data directory {}", opts.data_dir.display());
+    let data_dir = config
+        .data_dir
+        .clone()
+        .ok_or_else(|| eyre!("--data-dir or data_dir in the config file must be set"))?;
+    info!("Using data directory {}", data_dir.display());
 
-    fs::create_dir_all(&opts.data_dir).await?;
+    fs::create_dir_all(&data_dir).await?;
 
-    let root_token_file = opts.data_dir.join("root_token");
+    let root_token_file = config
+        .auth
+        .root_token_file
+        .clone()
+        .unwrap_or_else(|| data_dir.join("root_token"));
     let root_token = match fs::read_to_string(&root_token_file).await {
         Ok(token) => {
             info!("Loaded root token from {}", root_token_file.display());
@@ -80,7 +332,7 @@ async fn main() -> eyre::Result<()> {
             let token = Authorizer::random_root_token();
             let mut contents = token.as_bytes().to_vec();
             contents.push(b'\n');
-            fs::write(opts.data_dir.join("root_token"), contents).await?;
+            fs::write(&root_token_file, contents).await?;
             warn!(
                 "No root token found. A new token was generated and written to {}",
                 root_token_file.display()
@@ -91,137 +343,349 @@ async fn main() -> eyre::Result<()> {
     };
     let authorizer = Authorizer::new(root_token);
 
-    let user_db = UserDb::open(opts.data_dir.join("users"))?;
-    let docs_db = DocDb::open(opts.data_dir.join("docs"))?;
-    let doc_manager = DocManager::new(docs_db);
-
-    let tls_identity = if let Some(path) = opts.tls_identity {
-        let password = opts
-            .tls_identity_password
-            .as_ref()
-            .ok_or_else(|| eyre::eyre!("--tls-identity-password must be provided"))?;
-        debug!("Loading TLS identity from {}", path.display());
-        let mut file = File::open(path).await?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf).await?;
-        tls::Identity::from_pkcs12(&buf, password)?
-    } else {
-        warn!("No --tls-identity provided, using self-signed identity");
-        tls::Identity::from_pkcs12(SELF_SIGNED_IDENTITY, "password")?
-    };
-    let tls_acceptor = tls::TlsAcceptor::new(tls_identity)?;
-    let tls_acceptor = Arc::new(tokio_tls::TlsAcceptor::from(tls_acceptor));
-
-    let listener = TcpListener::bind(("0.0.0.0", opts.port)).await?;
-    info!(
-        "Listening on {} for the shrubbery protocol (no TLS)",
-        listener.local_addr()?
-    );
-
-    let secure_listener = TcpListener::bind(("0.0.0.0", opts.secure_port)).await?;
-    info!(
-        "Listening on {} for the shrubbery protocol (TLS)",
-        secure_listener.local_addr()?
-    );
-
-    let websocket_listener = TcpListener::bind(("0.0.0.0", opts.websocket_port)).await?;
-    info!(
-        "Listening on ws://{} as a HTTP/WebSocket server",
-        websocket_listener.local_addr()?
-    );
+    let shutdown = Shutdown::new(Duration::from_secs(config.shutdown.reconnect_hint));
 
-    let websocket_secure_listener =
-        TcpListener::bind(("0.0.0.0", opts.websocket_secure_port)).await?;
-    info!(
-        "Listening on on wss://{} as a HTTPS/WebSocket Secure server",
-        websocket_secure_listener.local_addr()?
+    let user_db = UserDb::open(data_dir.join("users"), &config.storage)?;
+    let docs_db = DocDb::open(data_dir.join("docs"), &config.storage)?;
+    let audit_db = AuditDb::open(data_dir.join("audit"), &config.storage)?;
+    let metrics = Metrics::new();
+    let doc_manager = DocManager::new(
+        docs_db.clone(),
+        config.presence(),
+        shutdown.clone(),
+        metrics.clone(),
     );
-
-    let shrub_processor = socket_processor::SocketProcessor::<TcpStream>::new(
+    let handlers = Handlers::new(
         authorizer.clone(),
+        docs_db.clone(),
+        audit_db.clone(),
         doc_manager.clone(),
-        user_db.clone(),
+        Connections::new(),
+        server_info(&config),
+        metrics.clone(),
     );
-    let shrubs_processor = socket_processor::SocketProcessor::<TlsStream<TcpStream>>::new(
-        authorizer.clone(),
+    let metrics_token = config
+        .auth
+        .metrics_token()?
+        .map(|token| token.expose().to_string());
+    let metrics_endpoint = MetricsEndpoint::new(
+        handlers.clone(),
         doc_manager.clone(),
         user_db.clone(),
+        docs_db.clone(),
+        metrics_token.clone(),
     );
-    let http_processor = socket_processor::SocketProcessor::new(
-        authorizer.clone(),
-        doc_manager.clone(),
+    let health = HealthEndpoints::new(
+        handlers.clone(),
         user_db.clone(),
+        docs_db.clone(),
+        audit_db.clone(),
+        shutdown.clone(),
+        config.limits.overload_sessions,
     );
-    let tls_processor = socket_processor::SocketProcessor::new(
-        authorizer.clone(),
+    health.probe_periodically();
+    let sessions = SessionStore::new(
+        Duration::from_secs(config.limits.resume_window),
+        shutdown.clone(),
+    );
+    let outbound = config.outbound();
+
+    let tls_acceptor = ReloadableTlsAcceptor::load(config.tls.identity()?).await?;
+    #[cfg(unix)]
+    tls_acceptor.reload_on_sighup(&shutdown)?;
+    let client_cert_authorizer = match &config.tls.client_users {
+        Some(path) => {
+            debug!("Loading client certificate users from {}", path.display());
+            let json = fs::read(path).await?;
+            Some(ClientCertAuthorizer::from_json(&json)?)
+        }
+        None => None,
+    };
+
+    for listener in &config.listeners {
+        listener.validate()?;
+    }
+    // Metrics are served on the HTTP listeners only if they have no listener of their own and are
+    // protected by a token
+    let has_metrics_listener = config
+        .listeners
+        .iter()
+        .any(|listener| listener.protocol == ListenerProtocol::Metrics);
+    let public_metrics = match (has_metrics_listener, &metrics_token) {
+        (false, Some(_)) => Some(metrics_endpoint.clone()),
+        _ => None,
+    };
+
+    if let Some(path) = &config.admin_socket {
+        start_admin_socket(
+            path,
+            &handlers,
+            &doc_manager,
+            &user_db,
+            &sessions,
+            outbound,
+            &shutdown,
+        )?;
+    }
+
+    let assets = if let Some(dir) = &config.http.static_dir {
+        info!("Serving static files from {}", dir.display());
+        StaticAssets::directory(dir)
+    } else {
+        let mut files: Vec<_> = CORE_WASM
+            .map(|wasm| ("core.wasm", wasm))
+            .into_iter()
+            .collect();
+        files.extend_from_slice(STATIC_BUNDLE);
+        StaticAssets::embedded(files)
+    };
+
+    let websocket_compression = config.http.websocket_compression;
+    let services = Services {
+        connections: handlers.connections().clone(),
+        shrub: SocketProcessor::new(
+            handlers.clone(),
+            doc_manager.clone(),
+            user_db.clone(),
+            sessions.clone(),
+            outbound,
+            shutdown.clone(),
+        ),
+        http: HttpMultiplexer::new(
+            assets.clone(),
+            handlers.clone(),
+            SocketProcessor::new(
+                handlers.clone(),
+                doc_manager.clone(),
+                user_db.clone(),
+                sessions.clone(),
+                outbound,
+                shutdown.clone(),
+            ),
+            health.clone(),
+            public_metrics.clone(),
+            websocket_compression,
+        ),
+        https: HttpMultiplexer::new(
+            assets.clone(),
+            handlers.clone(),
+            SocketProcessor::new(
+                handlers.clone(),
+                doc_manager.clone(),
+                user_db.clone(),
+                sessions.clone(),
+                outbound,
+                shutdown.clone(),
+            ),
+            health.clone(),
+            public_metrics.clone(),
+            websocket_compression,
+        ),
+        unified: ProtocolSniffer::new(
+            tls_acceptor.clone(),
+            client_cert_authorizer.clone(),
+            HttpMultiplexer::new(
+                assets,
+                handlers.clone(),
+                SocketProcessor::new(
+                    handlers.clone(),
+                    doc_manager.clone(),
+                    user_db.clone(),
+                    sessions.clone(),
+                    outbound,
+                    shutdown.clone(),
+                ),
+                health.clone(),
+                public_metrics,
+                websocket_compression,
+            ),
+            SocketProcessor::new(
+                handlers,
+                doc_manager.clone(),
+                user_db.clone(),
+                sessions.clone(),
+                outbound,
+                shutdown.clone(),
+            ),
+        ),
+        metrics: metrics_endpoint,
+        health,
+        tls_acceptor,
+        client_certs: client_cert_authorizer,
+    };
+
+    if let Some(path) = &config.listeners_file {
+        // Don't leave the previous run's addresses for anything waiting on the file
+        let _ = fs::remove_file(path).await;
+    }
+    let mut listeners = Vec::new();
+    for &listener in &config.listeners {
+        let listener = Listener::bind(listener, services.clone(), shutdown.clone())
+            .wrap_err_with(|| format!("failed to listen on {}", listener))?;
+        info!("Listening on {}", listener.bound());
+        listeners.push(listener);
+    }
+    if listeners.is_empty() {
+        warn!("No listeners are configured");
+    }
+    if let Some(path) = &config.listeners_file {
+        write_listeners_file(path, &listeners).await?;
+    }
+    for listener in listeners {
+        shutdown.spawn(listener.serve());
+    }
+
+    shutdown_signal().await?;
+
+    // Listeners stay open meanwhile, so clients aren't refused before load balancers notice.
+    // Another signal skips the wait.
+    let pre_drain = Duration::from_secs(config.shutdown.pre_drain);
+    if !pre_drain.is_zero() {
+        info!("Failing readiness for {:?} before shutting down", pre_drain);
+        shutdown.announce();
+        select! {
+            _ = tokio::time::sleep(pre_drain) => {}
+            res = shutdown_signal() => res?,
+        }
+    }
+
+    info!("Shutting down");
+    shutdown.trigger();
+    let deadline = Duration::from_secs(config.shutdown.timeout);
+    if !shutdown.drain(deadline).await {
+        warn!(
+            "Shutdown deadline of {:?} exceeded with {} tasks remaining",
+            deadline,
+            shutdown.remaining()
+        );
+    }
+
+    user_db.flush()?;
+    docs_db.flush()?;
+    audit_db.flush()?;
+    info!("Shutdown complete");
+
+    Ok(())
+}
+
+fn init_logging(format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+fn server_info(config: &Config) -> ServerInfo {
+    let mut features = vec!["errorCodes".to_string()];
+    if config.limits.resume_window > 0 {
+        features.push("resume".to_string());
+    }
+    if config.http.websocket_compression {
+        features.push("websocketCompression".to_string());
+    }
+    if config.tls.client_ca.is_some() {
+        features.push("clientCertificates".to_string());
+    }
+    if CORE_WASM.is_some() {
+        features.push("coreWasm".to_string());
+    }
+    ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_versions: vec!["shrub1".to_string()],
+        encodings: vec!["json".to_string()],
+        features,
+        limits: ServerLimits {
+            max_frame_length: codec::MAX_LENGTH,
+            outbound_queue: config.limits.outbound_queue,
+            presence_ttl_seconds: config.presence.ttl,
+            presence_interval_seconds: config.presence.interval,
+            resume_window_seconds: config.limits.resume_window,
+        },
+    }
+}
+
+#[cfg(unix)]
+fn start_admin_socket(
+    path: &std::path::Path,
+    handlers: &Handlers,
+    doc_manager: &DocManager,
+    user_db: &UserDb,
+    sessions: &SessionStore,
+    outbound: OutboundConfig,
+    shutdown: &Shutdown,
+) -> eyre::Result<()> {
+    let processor = socket_processor::SocketProcessor::new(
+        handlers.clone(),
         doc_manager.clone(),
         user_db.clone(),
+        sessions.clone(),
+        outbound,
+        shutdown.clone(),
     );
+    let admin_socket = AdminSocket::bind(path, processor, shutdown.clone())?;
+    info!(
+        "Listening on {} for local administration",
+        admin_socket.path().display()
+    );
+    shutdown.spawn(admin_socket.serve());
+    Ok(())
+}
 
-    let http_muxer = HttpMultiplexer::<TcpStream>::new(CORE_WASM, http_processor);
-    let tls_muxer = HttpMultiplexer::<TlsStream<TcpStream>>::new(CORE_WASM, tls_processor);
+#[cfg(not(unix))]
+fn start_admin_socket(
+    _path: &std::path::Path,
+    _handlers: &Handlers,
+    _doc_manager: &DocManager,
+    _user_db: &UserDb,
+    _sessions: &SessionStore,
+    _outbound: OutboundConfig,
+    _shutdown: &Shutdown,
+) -> eyre::Result<()> {
+    Err(eyre::eyre!("--admin-socket is only supported on unix"))
+}
+
+/// Writes the bound addresses whole and then renames the file into place, so anything waiting
+/// for it never reads part of it.
+async fn write_listeners_file(path: &Path, listeners: &[Listener]) -> eyre::Result<()> {
+    let contents: String = listeners
+        .iter()
+        .map(|listener| format!("{}\n", listener.bound()))
+        .collect();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)
+        .await
+        .wrap_err_with(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .await
+        .wrap_err_with(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
 
-    loop {
+/// Resolves on ctrl-c, or on SIGTERM on unix.
+async fn shutdown_signal() -> eyre::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .map_err(|err| eyre::eyre!("Failed to listen for SIGTERM: {}", err))?;
         select! {
             res = signal::ctrl_c() => {
                 res.map_err(|err| eyre::eyre!("Failed to listen for ctrl-c: {}", err))?;
                 info!("Received ctrl-c");
-                break;
             }
-            // res = listener.accept() => {
-            //     let (socket, addr) = res?;
-            //     let processor = shrub_processor.clone();
-            //     tokio::spawn(async move {
-            //         let socket = match FramedConnection::accept_shrub(socket).await {
-            //             Ok(socket) => socket,
-            //             Err(err) => {
-            //                 info!("Connection error: {}", err);
-            //                 return;
-            //             }
-            //         };
-            //         if let Err(err) = Session::accept(shared, socket).await {
-            //             info!("Connection error: {}", err);
-            //         }
-            //     });
-            // }
-            // res = secure_listener.accept() => {
-            //     let (socket, addr) = res?;
-            //     info!("Accepting connection from {} for the shrubbery protocol (TLS)", addr);
-            //     let acceptor = tls_acceptor.clone();
-            //     let shared = shared.clone();
-            //     tokio::spawn(async move {
-            //         let socket = match FramedConnection::accept_shrub_secure(socket, &acceptor).await {
-            //             Ok(socket) => socket,
-            //             Err(err) => {
-            //                 info!("Connection error: {}", err);
-            //                 return;
-            //             }
-            //         };
-            //         if let Err(err) = Session::accept(shared, socket).await {
-            //             info!("Connection error: {}", err);
-            //         }
-            //     });
-            // }
-            res = websocket_listener.accept() => {
-                let (socket, _) = res?;
-                let mux = http_muxer.clone();
-                tokio::spawn(async move {
-                    mux.handle(socket).await;
-                });
-            }
-            res = websocket_secure_listener.accept() => {
-                let (socket, _) = res?;
-                let mux = tls_muxer.clone();
-                let tls_acceptor = tls_acceptor.clone();
-                tokio::spawn(async move {
-                    let Ok(socket) = tls_acceptor.accept(socket).await else {
-                        return;
-                    };
-                    mux.handle(socket).await;
-                });
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM");
             }
         }
     }
-
+    #[cfg(not(unix))]
+    {
+        signal::ctrl_c()
+            .await
+            .map_err(|err| eyre::eyre!("Failed to listen for ctrl-c: {}", err))?;
+        info!("Received ctrl-c");
+    }
     Ok(())
 }
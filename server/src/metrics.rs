@@ -0,0 +1,255 @@
+//! Counters for the Prometheus `/metrics` endpoint.
+//!
+//! Counts are kept here as they happen. Gauges that can be read from elsewhere, such as the
+//! number of authenticated sessions, are read when the endpoint is scraped instead.
+
+use crate::connections::Transport;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the frame processing latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Metrics(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    connections: [AtomicI64; Transport::ALL.len()],
+    frames_in: Mutex<BTreeMap<&'static str, u64>>,
+    frames_out: Mutex<BTreeMap<&'static str, u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    presence_dropped_doc: AtomicU64,
+    presence_dropped_outbound: AtomicU64,
+    auth_failures_token: AtomicU64,
+    auth_failures_resume: AtomicU64,
+}
+
+/// Where presence was dropped because a connection wasn't keeping up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceDrop {
+    /// The doc worker couldn't hand presence to the connection
+    Doc,
+    /// The connection's outbound queue was full
+    Outbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    Token,
+    Resume,
+}
+
+/// Counts a connection as open until dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    metrics: Metrics,
+    transport: Transport,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection(&self, transport: Transport) -> ConnectionGuard {
+        self.0.connections[transport as usize].fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: self.clone(),
+            transport,
+        }
+    }
+
+    pub fn frame_received(&self, name: &'static str) {
+        *self.0.frames_in.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    pub fn frame_sent(&self, name: &'static str) {
+        *self.0.frames_out.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    /// Records how long processing a received frame took.
+    pub fn frame_processed(&self, name: &'static str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut latency = self.0.latency.lock().unwrap();
+        let histogram = latency.entry(name).or_default();
+        for (bucket, &bound) in histogram.buckets.iter_mut().zip(&LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    pub fn presence_dropped(&self, at: PresenceDrop) {
+        let counter = match at {
+            PresenceDrop::Doc => &self.0.presence_dropped_doc,
+            PresenceDrop::Outbound => &self.0.presence_dropped_outbound,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self, kind: AuthFailure) {
+        let counter = match kind {
+            AuthFailure::Token => &self.0.auth_failures_token,
+            AuthFailure::Resume => &self.0.auth_failures_resume,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes everything counted here to `out`.
+    pub fn encode(&self, out: &mut Encoder) {
+        out.family(
+            "shrub_connections",
+            "gauge",
+            "Open connections by transport",
+        );
+        for transport in Transport::ALL {
+            let open = self.0.connections[transport as usize].load(Ordering::Relaxed);
+            out.sample(
+                "shrub_connections",
+                &[("transport", transport.label())],
+                open,
+            );
+        }
+
+        for (name, frames, help) in [
+            (
+                "shrub_frames_received_total",
+                &self.0.frames_in,
+                "Frames received by type",
+            ),
+            (
+                "shrub_frames_sent_total",
+                &self.0.frames_out,
+                "Frames queued to be sent by type",
+            ),
+        ] {
+            out.family(name, "counter", help);
+            for (&frame, count) in frames.lock().unwrap().iter() {
+                out.sample(name, &[("type", frame)], count);
+            }
+        }
+
+        out.family(
+            "shrub_presence_dropped_total",
+            "counter",
+            "Presence updates dropped because a connection wasn't keeping up",
+        );
+        for (at, counter) in [
+            ("doc", &self.0.presence_dropped_doc),
+            ("outbound", &self.0.presence_dropped_outbound),
+        ] {
+            let dropped = counter.load(Ordering::Relaxed);
+            out.sample("shrub_presence_dropped_total", &[("at", at)], dropped);
+        }
+
+        out.family(
+            "shrub_auth_failures_total",
+            "counter",
+            "Rejected tokens and resume attempts",
+        );
+        for (kind, counter) in [
+            ("token", &self.0.auth_failures_token),
+            ("resume", &self.0.auth_failures_resume),
+        ] {
+            let failures = counter.load(Ordering::Relaxed);
+            out.sample("shrub_auth_failures_total", &[("kind", kind)], failures);
+        }
+
+        out.family(
+            "shrub_frame_processing_seconds",
+            "histogram",
+            "Time taken to process received frames by type",
+        );
+        for (&frame, histogram) in self.0.latency.lock().unwrap().iter() {
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                out.sample(
+                    "shrub_frame_processing_seconds_bucket",
+                    &[("type", frame), ("le", &bound.to_string())],
+                    bucket,
+                );
+            }
+            out.sample(
+                "shrub_frame_processing_seconds_bucket",
+                &[("type", frame), ("le", "+Inf")],
+                histogram.count,
+            );
+            out.sample(
+                "shrub_frame_processing_seconds_sum",
+                &[("type", frame)],
+                histogram.sum,
+            );
+            out.sample(
+                "shrub_frame_processing_seconds_count",
+                &[("type", frame)],
+                histogram.count,
+            );
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.0.connections[self.transport as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Builds a page in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    out: String,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a metric family. Its samples must follow before the next family starts.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"", label);
+                for c in value.chars() {
+                    match c {
+                        '\\' => self.out.push_str("\\\\"),
+                        '"' => self.out.push_str("\\\""),
+                        '\n' => self.out.push_str("\\n"),
+                        c => self.out.push(c),
+                    }
+                }
+                self.out.push('"');
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
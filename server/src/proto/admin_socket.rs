@@ -0,0 +1,174 @@
+use crate::connections::{connection_span, Origin, Transport};
+use crate::proto::listener::ACCEPT_ERROR_DELAY;
+use crate::proto::socket_processor::SocketProcessor;
+use crate::shutdown::Shutdown;
+use crate::state::authorizer;
+use crate::FramedConnection;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::select;
+use tracing::{debug, info, warn, Instrument};
+
+/// A Unix domain socket speaking the shrub protocol for local administration.
+///
+/// Peers are authenticated by their credentials (`SO_PEERCRED`) instead of a token: a peer
+/// running as the same user as the server gets a root session, anyone else is disconnected.
+pub struct AdminSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    uid: u32,
+    processor: SocketProcessor<FramedConnection>,
+    shutdown: Shutdown,
+}
+
+impl AdminSocket {
+    /// Binds to `path`, replacing a socket left behind by a previous run.
+    pub fn bind(
+        path: impl Into<PathBuf>,
+        processor: SocketProcessor<FramedConnection>,
+        shutdown: Shutdown,
+    ) -> eyre::Result<Self> {
+        let path = path.into();
+        remove_stale_socket(&path)?;
+        let listener = bind_private(&path)?;
+        // SAFETY: geteuid has no preconditions and cannot fail
+        let uid = unsafe { libc::geteuid() };
+        Ok(Self {
+            listener,
+            path,
+            uid,
+            processor,
+            shutdown,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accepts connections until shutdown, then removes the socket file.
+    pub async fn serve(self) {
+        loop {
+            let socket = select! {
+                res = self.listener.accept() => res,
+                _ = self.shutdown.triggered() => break,
+            };
+            let socket = match socket {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    warn!("Error accepting admin connection: {}", err);
+                    select! {
+                        _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => continue,
+                        _ = self.shutdown.triggered() => break,
+                    }
+                }
+            };
+            if !self.is_authorized(&socket) {
+                continue;
+            }
+            let id = self.processor.connections().next_id();
+            let span = connection_span(id, self.path.display(), None);
+            let processor = self.processor.clone();
+            self.shutdown.spawn(
+                async move {
+                    let socket = match FramedConnection::accept_shrub_unix(socket).await {
+                        Ok(socket) => socket,
+                        Err(err) => {
+                            info!("Admin connection error: {}", err);
+                            return;
+                        }
+                    };
+                    processor
+                        .accept_authenticated(
+                            socket,
+                            authorizer::Entry::root(),
+                            Origin::new(id, Transport::Admin, None),
+                        )
+                        .await;
+                }
+                .instrument(span),
+            );
+        }
+
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove admin socket {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    fn is_authorized(&self, socket: &UnixStream) -> bool {
+        match socket.peer_cred() {
+            Ok(cred) if cred.uid() == self.uid => {
+                debug!(
+                    "Accepting admin connection from uid {} (pid {:?})",
+                    cred.uid(),
+                    cred.pid()
+                );
+                true
+            }
+            Ok(cred) => {
+                warn!(
+                    "Rejecting admin connection from uid {} (pid {:?}), expected uid {}",
+                    cred.uid(),
+                    cred.pid(),
+                    self.uid
+                );
+                false
+            }
+            Err(err) => {
+                warn!(
+                    "Rejecting admin connection with unknown credentials: {}",
+                    err
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Binds a socket only its owner can connect to at `path`.
+///
+/// Binding creates the socket with the umask applied, and changing its mode afterwards would leave
+/// a window where anyone could connect. So it's bound in a new 0700 directory, made 0600 there and
+/// then moved into place. Setting the umask instead would affect files other threads create
+/// meanwhile, such as RocksDB's.
+fn bind_private(path: &Path) -> eyre::Result<UnixListener> {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(format!(".{}.tmp", std::process::id()));
+    let dir = PathBuf::from(dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let temp = dir.join("socket");
+    let res = UnixListener::bind(&temp).and_then(|listener| {
+        std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&temp, path)?;
+        Ok(listener)
+    });
+    if let Err(err) = std::fs::remove_dir_all(&dir) {
+        warn!("Failed to remove {}: {}", dir.display(), err);
+    }
+    Ok(res?)
+}
+
+fn remove_stale_socket(path: &Path) -> eyre::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(eyre::eyre!(
+                    "{} is in use by another server",
+                    path.display()
+                ));
+            }
+            debug!("Removing stale admin socket {}", path.display());
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Ok(_) => Err(eyre::eyre!("{} exists and is not a socket", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
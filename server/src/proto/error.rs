@@ -0,0 +1,90 @@
+//! Errors replied to frames, each with the stable code clients match on.
+
+use crate::handlers;
+use shrubbery_common::frame::{ErrorCode, FrameType};
+use shrubbery_common::DocId;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum Error {
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    BadRequest(String),
+    DocNotOpen(DocId),
+    CannotResume,
+    InvalidFrame(String),
+    UnknownFrame,
+    UnexpectedFrame(String),
+    Internal(eyre::Report),
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::Forbidden => ErrorCode::Forbidden,
+            Error::NotFound => ErrorCode::NotFound,
+            Error::BadRequest(_) => ErrorCode::BadRequest,
+            Error::DocNotOpen(_) => ErrorCode::DocNotOpen,
+            Error::CannotResume => ErrorCode::CannotResume,
+            Error::InvalidFrame(_) => ErrorCode::InvalidFrame,
+            Error::UnknownFrame => ErrorCode::UnknownFrame,
+            Error::UnexpectedFrame(_) => ErrorCode::UnexpectedFrame,
+            Error::Internal(_) => ErrorCode::Internal,
+        }
+    }
+
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::DocNotOpen(doc) => Some(serde_json::json!({ "doc": doc })),
+            _ => None,
+        }
+    }
+
+    pub fn to_frame(&self) -> FrameType {
+        FrameType::Error {
+            code: self.code(),
+            error: self.to_string(),
+            details: self.details(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Unauthorized => write!(f, "invalid token"),
+            Error::Forbidden => write!(f, "forbidden"),
+            Error::NotFound => write!(f, "not found"),
+            Error::BadRequest(message) => write!(f, "{}", message),
+            Error::DocNotOpen(doc) => write!(f, "doc {} not open", doc),
+            Error::CannotResume => write!(f, "cannot resume session"),
+            Error::InvalidFrame(message) => write!(f, "invalid frame: {}", message),
+            Error::UnknownFrame => write!(f, "unknown frame type"),
+            Error::UnexpectedFrame(message) => write!(f, "unexpected frame: {}", message),
+            // The details are logged, not shown to clients
+            Error::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<handlers::Error> for Error {
+    fn from(err: handlers::Error) -> Self {
+        match err {
+            handlers::Error::Unauthorized => Error::Unauthorized,
+            handlers::Error::Forbidden => Error::Forbidden,
+            handlers::Error::NotFound => Error::NotFound,
+            handlers::Error::BadRequest(message) => Error::BadRequest(message),
+            handlers::Error::Internal(err) => Error::Internal(err),
+        }
+    }
+}
+
+impl From<eyre::Report> for Error {
+    fn from(err: eyre::Report) -> Self {
+        Error::Internal(err)
+    }
+}
@@ -1,7 +1,8 @@
+use crate::proto::websocket_deflate::{DeflateParams, DeflateStream};
 use crate::Frame;
 use pin_project::pin_project;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{
     tungstenite::{Error, Message},
@@ -12,10 +13,16 @@ use tracing::debug;
 #[pin_project]
 pub struct Adapter<S> {
     #[pin]
-    inner: WebSocketStream<S>,
+    inner: Transport<S>,
     shrub_handshake_state: ShrubHandshakeState,
 }
 
+#[pin_project(project = TransportProj)]
+enum Transport<S> {
+    Plain(#[pin] WebSocketStream<S>),
+    Deflate(#[pin] DeflateStream<S>),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ShrubHandshakeState {
     Pre,
@@ -23,15 +30,77 @@ enum ShrubHandshakeState {
     Missing,
 }
 
-impl<S> Adapter<S> {
+impl<S> Adapter<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
     pub(super) fn new(inner: WebSocketStream<S>) -> Self {
         Self {
-            inner,
+            inner: Transport::Plain(inner),
+            shrub_handshake_state: ShrubHandshakeState::Pre,
+        }
+    }
+
+    pub(super) fn new_deflate(socket: S, params: DeflateParams) -> Self {
+        Self {
+            inner: Transport::Deflate(DeflateStream::new(socket, params)),
             shrub_handshake_state: ShrubHandshakeState::Pre,
         }
     }
 }
 
+impl<S> futures::Stream for Transport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = std::io::Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.project() {
+            TransportProj::Plain(inner) => match ready!(inner.poll_next(cx)) {
+                Some(Err(Error::ConnectionClosed)) | None => Poll::Ready(None),
+                Some(res) => Poll::Ready(Some(res.map_err(transpose_to_io_error))),
+            },
+            TransportProj::Deflate(inner) => inner.poll_next(cx),
+        }
+    }
+}
+
+impl<S> futures::Sink<Message> for Transport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.project() {
+            TransportProj::Plain(inner) => inner.poll_ready(cx).map_err(transpose_to_io_error),
+            TransportProj::Deflate(inner) => inner.poll_ready(cx),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        match self.project() {
+            TransportProj::Plain(inner) => inner.start_send(item).map_err(transpose_to_io_error),
+            TransportProj::Deflate(inner) => inner.start_send(item),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.project() {
+            TransportProj::Plain(inner) => inner.poll_flush(cx).map_err(transpose_to_io_error),
+            TransportProj::Deflate(inner) => inner.poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.project() {
+            TransportProj::Plain(inner) => inner.poll_close(cx).map_err(transpose_to_io_error),
+            TransportProj::Deflate(inner) => inner.poll_close(cx),
+        }
+    }
+}
+
 impl<S> futures::Stream for Adapter<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
@@ -39,58 +108,51 @@ where
     type Item = std::io::Result<Frame>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        let res = match this.inner.poll_next(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(res) => res,
-        };
-        let res = match res {
-            Some(res) => res,
-            None => return Poll::Pending,
-        };
-        let msg = match res {
-            Ok(msg) => msg,
-            Err(Error::ConnectionClosed) => return Poll::Ready(None),
-            Err(err) => return Poll::Ready(Some(Err(transpose_to_io_error(err)))),
-        };
-        let msg = match msg {
-            Message::Text(text) => text,
-            Message::Binary(_) => {
-                // ignore binary frames for forwards compatibility
-                debug!("ignoring binary websocket message");
-                return Poll::Pending;
-            }
-            _ => return Poll::Pending, // ignore control frames
-        };
+        let mut this = self.project();
+        loop {
+            let msg = match ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(Ok(msg)) => msg,
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            };
+            let msg = match msg {
+                Message::Text(text) => text,
+                Message::Binary(_) => {
+                    // ignore binary frames for forwards compatibility
+                    debug!("ignoring binary websocket message");
+                    continue;
+                }
+                _ => continue, // ignore control frames
+            };
 
-        match this.shrub_handshake_state {
-            ShrubHandshakeState::Pre => {
-                if msg != "shrub1\n" {
+            match this.shrub_handshake_state {
+                ShrubHandshakeState::Pre => {
+                    if msg != "shrub1\n" {
+                        return Poll::Ready(Some(Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "expected 'shrub' handshake",
+                        ))));
+                    }
+                    *this.shrub_handshake_state = ShrubHandshakeState::Post;
+                }
+                ShrubHandshakeState::Missing => {
                     return Poll::Ready(Some(Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
-                        "expected 'shrub' handshake",
+                        "missing 'shrub' handshake",
                     ))));
                 }
-                *this.shrub_handshake_state = ShrubHandshakeState::Post;
-                return Poll::Pending;
-            }
-            ShrubHandshakeState::Missing => {
-                return Poll::Ready(Some(Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "missing 'shrub' handshake",
-                ))));
-            }
-            ShrubHandshakeState::Post => {
-                let frame = match serde_json::from_str(&msg) {
-                    Ok(frame) => frame,
-                    Err(err) => {
-                        return Poll::Ready(Some(Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            err,
-                        ))))
-                    }
-                };
-                Poll::Ready(Some(Ok(frame)))
+                ShrubHandshakeState::Post => {
+                    let frame = match Frame::parse(&msg) {
+                        Ok(frame) => frame,
+                        Err(err) => {
+                            return Poll::Ready(Some(Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                err,
+                            ))))
+                        }
+                    };
+                    return Poll::Ready(Some(Ok(frame)));
+                }
             }
         }
     }
@@ -103,10 +165,7 @@ where
     type Error = std::io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project()
-            .inner
-            .poll_ready(cx)
-            .map_err(transpose_to_io_error)
+        self.project().inner.poll_ready(cx)
     }
 
     fn start_send(self: Pin<&mut Self>, item: Frame) -> Result<(), Self::Error> {
@@ -114,30 +173,21 @@ where
             Ok(msg) => msg,
             Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
         };
-        self.project()
-            .inner
-            .start_send(Message::Text(msg))
-            .map_err(transpose_to_io_error)
+        self.project().inner.start_send(Message::Text(msg))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project()
-            .inner
-            .poll_flush(cx)
-            .map_err(transpose_to_io_error)
+        self.project().inner.poll_flush(cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.project()
-            .inner
-            .poll_close(cx)
-            .map_err(transpose_to_io_error)
+        self.project().inner.poll_close(cx)
     }
 }
 
 fn transpose_to_io_error(err: Error) -> std::io::Error {
     match err {
         Error::Io(err) => err,
-        err => std::io::Error::new(std::io::ErrorKind::Other, err),
+        err => std::io::Error::other(err),
     }
 }
@@ -0,0 +1,174 @@
+use crate::db::{AuditDb, DocDb, UserDb};
+use crate::handlers::Handlers;
+use crate::proto::http_multiplexer::{method_not_allowed, text_response};
+use crate::shutdown::Shutdown;
+use bytes::Bytes;
+use http::{Method, StatusCode};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tracing::{debug, warn};
+
+/// How often the databases are probed.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a database counts as writable after passing a probe, so one stuck on a hung disk
+/// fails readiness rather than keeping its last result.
+const PROBE_MAX_AGE: Duration = Duration::from_secs(15);
+
+/// When each database last passed its probe, by name. Cleared when a probe fails.
+type Probes = [(&'static str, Option<Instant>); 3];
+
+/// `GET /healthz` and `GET /readyz` for load balancers and orchestrators. Neither needs a token.
+///
+/// `/healthz` succeeds while the server is running. `/readyz` fails while the server shouldn't be
+/// sent new clients: a database can't be written, shutdown has started or the server is
+/// overloaded. It only says which checks pass, with the details logged at debug level.
+#[derive(Clone, Debug)]
+pub struct HealthEndpoints {
+    handlers: Handlers,
+    user_db: UserDb,
+    docs_db: DocDb,
+    audit_db: AuditDb,
+    probes: Arc<Mutex<Probes>>,
+    shutdown: Shutdown,
+    overload_sessions: Option<usize>,
+}
+
+impl HealthEndpoints {
+    /// The server counts as overloaded once `overload_sessions` sessions are authenticated.
+    pub fn new(
+        handlers: Handlers,
+        user_db: UserDb,
+        docs_db: DocDb,
+        audit_db: AuditDb,
+        shutdown: Shutdown,
+        overload_sessions: Option<usize>,
+    ) -> Self {
+        Self {
+            handlers,
+            user_db,
+            docs_db,
+            audit_db,
+            probes: Arc::new(Mutex::new([
+                ("users db", None),
+                ("docs db", None),
+                ("audit db", None),
+            ])),
+            shutdown,
+            overload_sessions,
+        }
+    }
+
+    /// Probes the databases every [`PROBE_INTERVAL`] until shutdown. `/readyz` fails until the
+    /// first probes pass.
+    pub fn probe_periodically(&self) {
+        let health = self.clone();
+        self.shutdown.spawn(async move {
+            let mut ticks = tokio::time::interval(PROBE_INTERVAL);
+            loop {
+                select! {
+                    _ = ticks.tick() => {}
+                    _ = health.shutdown.triggered() => return,
+                }
+                health.probe().await;
+            }
+        });
+    }
+
+    /// Writes to each database on a blocking thread, as the writes may wait for the disk.
+    async fn probe(&self) {
+        let (user_db, docs_db, audit_db) = (
+            self.user_db.clone(),
+            self.docs_db.clone(),
+            self.audit_db.clone(),
+        );
+        let results = tokio::task::spawn_blocking(move || {
+            [user_db.probe(), docs_db.probe(), audit_db.probe()]
+        })
+        .await;
+        let mut probes = self.probes.lock().unwrap();
+        let Ok(results) = results else {
+            warn!("Database probes panicked");
+            probes.iter_mut().for_each(|(_, passed)| *passed = None);
+            return;
+        };
+        for ((name, passed), res) in probes.iter_mut().zip(results) {
+            match res {
+                Ok(()) => *passed = Some(Instant::now()),
+                Err(err) => {
+                    warn!("Failed to probe the {}: {}", name, err);
+                    *passed = None;
+                }
+            }
+        }
+    }
+
+    /// Responds to `req` if it's for one of the health paths.
+    pub fn respond(&self, req: &http::Request<Bytes>) -> Option<http::Response<Bytes>> {
+        let path = req.uri().path();
+        if path != "/healthz" && path != "/readyz" {
+            return None;
+        }
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Some(method_not_allowed("GET, HEAD"));
+        }
+        if path == "/healthz" {
+            return Some(text_response(StatusCode::OK, "ok\n"));
+        }
+
+        let (ready, report) = self.readiness();
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        Some(text_response(status, report))
+    }
+
+    /// Runs every readiness check, returning whether they all passed and whether each one did.
+    fn readiness(&self) -> (bool, String) {
+        let mut ready = true;
+        let mut report = String::new();
+        let mut details = Vec::new();
+        let mut check = |name: &str, ok: bool, detail: String| {
+            ready &= ok;
+            let _ = writeln!(report, "{}: {}", name, if ok { "ok" } else { "failing" });
+            details.push(format!("{}: {}", name, detail));
+        };
+
+        for &(name, passed) in self.probes.lock().unwrap().iter() {
+            match passed {
+                Some(at) if at.elapsed() < PROBE_MAX_AGE => {
+                    check(name, true, format!("probed {:?} ago", at.elapsed()))
+                }
+                Some(at) => check(name, false, format!("last probed {:?} ago", at.elapsed())),
+                None => check(name, false, "last probe failed".to_string()),
+            }
+        }
+
+        if self.shutdown.is_announced() {
+            check("shutdown", false, "started".to_string());
+        } else {
+            check("shutdown", true, "not started".to_string());
+        }
+
+        let sessions = self.handlers.connections().len();
+        match self.overload_sessions {
+            Some(limit) => check(
+                "sessions",
+                sessions < limit,
+                format!("{} of {}", sessions, limit),
+            ),
+            None => check("sessions", true, sessions.to_string()),
+        }
+
+        debug!(
+            "{}: {}",
+            if ready { "ready" } else { "not ready" },
+            details.join(", ")
+        );
+        (ready, report)
+    }
+}
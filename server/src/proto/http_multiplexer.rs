@@ -1,153 +1,504 @@
+use crate::connections::Origin;
+use crate::handlers::Handlers;
+use crate::metrics::Metrics;
 use crate::proto::framed_websocket;
+use crate::proto::health::HealthEndpoints;
+use crate::proto::metrics_endpoint::MetricsEndpoint;
+use crate::proto::rest_api::RestApi;
 use crate::proto::socket_processor;
-use bytes::BytesMut;
-use http::response;
+use crate::proto::static_assets::StaticAssets;
+use crate::proto::websocket_deflate::DeflateParams;
+use crate::shutdown::Shutdown;
+use crate::state::authorizer;
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderName, Method, StatusCode, Version};
 use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::select;
 use tracing::{debug, trace};
 
+/// Maximum size of a request line and headers.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+const MAX_BODY_LEN: usize = 1024 * 1024;
+
+const MAX_HEADERS: usize = 64;
+
+/// How long a kept-alive connection may sit idle between requests. Idle connections are closed
+/// right away on shutdown.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct HttpMultiplexer<Socket> {
     processor: socket_processor::SocketProcessor<framed_websocket::Adapter<Socket>>,
-    core_wasm: &'static [u8],
+    assets: StaticAssets,
+    api: RestApi,
+    health: HealthEndpoints,
+    metrics_endpoint: Option<MetricsEndpoint>,
+    metrics: Metrics,
+    websocket_compression: bool,
 }
 
 impl<S> Clone for HttpMultiplexer<S> {
     fn clone(&self) -> Self {
         Self {
             processor: self.processor.clone(),
-            core_wasm: self.core_wasm,
+            assets: self.assets.clone(),
+            api: self.api.clone(),
+            health: self.health.clone(),
+            metrics_endpoint: self.metrics_endpoint.clone(),
+            metrics: self.metrics.clone(),
+            websocket_compression: self.websocket_compression,
         }
     }
 }
 
+enum Outcome {
+    Respond(http::Response<Bytes>),
+    Upgrade {
+        accept_key: String,
+        deflate: Option<DeflateParams>,
+    },
+}
+
 impl<Socket> HttpMultiplexer<Socket>
 where
     Socket: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
+    /// If `websocket_compression` is set, permessage-deflate is used for websocket connections
+    /// when the client offers it. `/metrics` is only served if `metrics` is set.
     pub fn new(
-        core_wasm: &'static [u8],
+        assets: StaticAssets,
+        handlers: Handlers,
         processor: socket_processor::SocketProcessor<framed_websocket::Adapter<Socket>>,
+        health: HealthEndpoints,
+        metrics: Option<MetricsEndpoint>,
+        websocket_compression: bool,
     ) -> Self {
         Self {
             processor,
-            core_wasm,
+            assets,
+            metrics: handlers.metrics().clone(),
+            api: RestApi::new(handlers),
+            health,
+            metrics_endpoint: metrics,
+            websocket_compression,
         }
     }
 
-    pub async fn handle(&self, mut socket: Socket) {
+    pub async fn handle(&self, socket: Socket, origin: Origin) {
+        self.handle_authenticated(socket, None, origin).await
+    }
+
+    /// Handles a connection whose peer may have been authenticated by its TLS client certificate.
+    ///
+    /// API requests without a bearer token and websockets act as `client_auth` instead of
+    /// requiring a token.
+    pub async fn handle_authenticated(
+        &self,
+        mut socket: Socket,
+        client_auth: Option<authorizer::Entry>,
+        origin: Origin,
+    ) {
+        let connection = self.metrics.connection(origin.transport);
+        let shutdown = self.processor.shutdown();
         let mut buf = BytesMut::new();
         loop {
-            let mut headers = [httparse::EMPTY_HEADER; 64];
-            if socket.read_buf(&mut buf).await.is_err() {
-                debug!("rejecting: failed to read");
-                return;
-            }
-            let mut req = httparse::Request::new(&mut headers);
-            match req.parse(&buf) {
-                Ok(httparse::Status::Complete(_)) => {}
-                Ok(httparse::Status::Partial) => {
-                    continue;
-                }
-                Err(e) => {
-                    debug!("rejecting: failed to parse: {:?}", e);
+            let req = match read_request(&mut socket, &mut buf, shutdown).await {
+                Ok(Some(req)) => req,
+                Ok(None) => return,
+                Err(status) => {
+                    let _ = write_response(&mut socket, error_response(status), false, false).await;
                     return;
                 }
-            }
-            trace!("parsed: {:?}", req);
-            let Some(method) = req.method else {
-                debug!("rejecting: missing method");
-                return;
-            };
-            let Some(path) = req.path else {
-                debug!("rejecting: missing path");
-                return;
             };
+            trace!("parsed: {:?}", req);
 
-            match (method, path) {
-                ("GET", "/core.wasm") => {
-                    self.respond_core_wasm(socket).await;
-                    return;
-                }
-                ("GET", "/socket") => {
-                    let mut version = None;
-                    let mut key = None;
-                    for header in &headers {
-                        match header.name {
-                            "Sec-WebSocket-Version" => version = Some(header.value),
-                            "Sec-WebSocket-Key" => key = Some(header.value),
-                            _ => {}
-                        }
-                    }
-                    if version != Some(b"13") {
-                        debug!("rejecting: unsupported websocket version: {:?}", version);
+            let keep_alive = wants_keep_alive(&req) && !shutdown.is_triggered();
+            let head_only = req.method() == Method::HEAD;
+
+            match self.route(&req, client_auth.as_ref(), origin.peer).await {
+                Outcome::Respond(response) => {
+                    let res = write_response(&mut socket, response, head_only, keep_alive).await;
+                    if res.is_err() || !keep_alive {
                         return;
                     }
-                    let Some(key) = key else {
-                        debug!("rejecting: missing websocket key");
-                        return;
-                    };
-                    self.accept_websocket(socket, key).await;
-                    return;
                 }
-                _ => {
-                    debug!("not found: {} {}", method, path);
-                    Self::respond_404(socket).await;
+                Outcome::Upgrade {
+                    accept_key,
+                    deflate,
+                } => {
+                    // The websocket is counted as a frame connection from here on
+                    drop(connection);
+                    let origin = origin.websocket();
+                    self.accept_websocket(socket, accept_key, deflate, client_auth, origin)
+                        .await;
                     return;
                 }
             }
         }
     }
 
-    async fn respond_core_wasm(&self, mut socket: Socket) {
-        let response = response::Builder::new()
-            .status(200)
-            .header("Content-Type", "application/wasm")
-            .header("Content-Length", self.core_wasm.len())
-            .body(())
-            .unwrap();
-        let buf = http_head(response);
-        let _ = socket.write_all(&buf).await;
-        let _ = socket.write_all(&self.core_wasm).await;
-    }
-
-    async fn accept_websocket(&self, mut socket: Socket, key: &[u8]) {
-        let response = response::Builder::new()
-            .status(101)
-            .header("Upgrade", "websocket")
-            .header("Connection", "Upgrade")
-            .header("Sec-WebSocket-Accept", derive_accept_key(key))
-            .body(())
-            .unwrap();
-        let buf = http_head(response);
-        let _ = socket.write_all(&buf).await;
-
-        let socket = tokio_tungstenite::WebSocketStream::from_raw_socket(
-            socket,
-            tokio_tungstenite::tungstenite::protocol::Role::Server,
-            None,
-        )
-        .await;
+    async fn route(
+        &self,
+        req: &http::Request<Bytes>,
+        client_auth: Option<&authorizer::Entry>,
+        peer: Option<SocketAddr>,
+    ) -> Outcome {
+        let method = req.method();
+        let path = req.uri().path();
+        if path == "/socket" {
+            return self.route_websocket(req);
+        }
+        if path.starts_with("/api/") {
+            return Outcome::Respond(self.api.respond(req, client_auth, peer).await);
+        }
+        if let Some(response) = self.health.respond(req) {
+            return Outcome::Respond(response);
+        }
+        if let (Some(metrics), "/metrics") = (&self.metrics_endpoint, path) {
+            return Outcome::Respond(metrics.respond(req));
+        }
+        if method != Method::GET && method != Method::HEAD {
+            return Outcome::Respond(method_not_allowed("GET, HEAD"));
+        }
+        match self.assets.respond(req).await {
+            Some(response) => Outcome::Respond(response),
+            None => {
+                debug!("not found: {} {}", method, path);
+                Outcome::Respond(error_response(StatusCode::NOT_FOUND))
+            }
+        }
+    }
+
+    fn route_websocket(&self, req: &http::Request<Bytes>) -> Outcome {
+        if req.method() != Method::GET {
+            return Outcome::Respond(method_not_allowed("GET"));
+        }
+        let headers = req.headers();
+        if !has_token(headers, header::UPGRADE, "websocket")
+            || !has_token(headers, header::CONNECTION, "upgrade")
+        {
+            debug!("rejecting: not a websocket upgrade");
+            let mut response = error_response(StatusCode::UPGRADE_REQUIRED);
+            response
+                .headers_mut()
+                .insert(header::UPGRADE, "websocket".parse().unwrap());
+            return Outcome::Respond(response);
+        }
+        let version = headers.get(header::SEC_WEBSOCKET_VERSION);
+        if version.map(|v| v.as_bytes()) != Some(b"13") {
+            debug!("rejecting: unsupported websocket version: {:?}", version);
+            let mut response = error_response(StatusCode::UPGRADE_REQUIRED);
+            response
+                .headers_mut()
+                .insert(header::SEC_WEBSOCKET_VERSION, "13".parse().unwrap());
+            return Outcome::Respond(response);
+        }
+        let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+            debug!("rejecting: missing websocket key");
+            return Outcome::Respond(error_response(StatusCode::BAD_REQUEST));
+        };
+        if !is_valid_websocket_key(key.as_bytes()) {
+            debug!("rejecting: invalid websocket key");
+            return Outcome::Respond(error_response(StatusCode::BAD_REQUEST));
+        }
 
-        self.processor
-            .accept(framed_websocket::Adapter::new(socket))
-            .await;
+        let deflate = if self.websocket_compression {
+            let extensions = headers
+                .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+                .iter()
+                .filter_map(|value| value.to_str().ok());
+            DeflateParams::negotiate(extensions)
+        } else {
+            None
+        };
+        Outcome::Upgrade {
+            accept_key: derive_accept_key(key.as_bytes()),
+            deflate,
+        }
     }
 
-    async fn respond_404(mut socket: Socket) {
-        let body = b"Not Found";
-        let response = response::Builder::new()
-            .status(404)
-            .header("Content-Type", "text/plain")
-            .header("Content-Length", body.len())
-            .body(())
-            .unwrap();
-        let mut buf = http_head(response);
-        buf.extend_from_slice(body);
-        let _ = socket.write_all(&buf).await;
+    async fn accept_websocket(
+        &self,
+        mut socket: Socket,
+        accept_key: String,
+        deflate: Option<DeflateParams>,
+        client_auth: Option<authorizer::Entry>,
+        origin: Origin,
+    ) {
+        let mut response = http::Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::SEC_WEBSOCKET_ACCEPT, accept_key);
+        if let Some(params) = &deflate {
+            response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, params.response_header());
+        }
+        let buf = http_head(&response.body(()).unwrap());
+        if socket.write_all(&buf).await.is_err() {
+            return;
+        }
+
+        let socket = match deflate {
+            Some(params) => {
+                debug!("accepting websocket with permessage-deflate");
+                framed_websocket::Adapter::new_deflate(socket, params)
+            }
+            None => framed_websocket::Adapter::new(
+                tokio_tungstenite::WebSocketStream::from_raw_socket(
+                    socket,
+                    tokio_tungstenite::tungstenite::protocol::Role::Server,
+                    None,
+                )
+                .await,
+            ),
+        };
+
+        match client_auth {
+            Some(entry) => {
+                self.processor
+                    .accept_authenticated(socket, entry, origin)
+                    .await
+            }
+            None => self.processor.accept(socket, origin).await,
+        }
     }
 }
 
-fn http_head(response: http::Response<()>) -> BytesMut {
+/// Serves only `/metrics` and the health endpoints on a connection, for a port kept separate from
+/// the public ones.
+pub async fn serve_monitoring<Socket>(
+    mut socket: Socket,
+    metrics: &MetricsEndpoint,
+    health: &HealthEndpoints,
+    shutdown: &Shutdown,
+) where
+    Socket: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    loop {
+        let req = match read_request(&mut socket, &mut buf, shutdown).await {
+            Ok(Some(req)) => req,
+            Ok(None) => return,
+            Err(status) => {
+                let _ = write_response(&mut socket, error_response(status), false, false).await;
+                return;
+            }
+        };
+        let keep_alive = wants_keep_alive(&req) && !shutdown.is_triggered();
+        let head_only = req.method() == Method::HEAD;
+        let response = match health.respond(&req) {
+            Some(response) => response,
+            None if req.uri().path() == "/metrics" => metrics.respond(&req),
+            None => error_response(StatusCode::NOT_FOUND),
+        };
+        let res = write_response(&mut socket, response, head_only, keep_alive).await;
+        if res.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Reads the next request on the connection.
+///
+/// Returns `Ok(None)` if the connection was closed, went idle or is idle when shutdown is
+/// triggered, and the status to reply with if the request can't be handled.
+async fn read_request<Socket>(
+    socket: &mut Socket,
+    buf: &mut BytesMut,
+    shutdown: &Shutdown,
+) -> Result<Option<http::Request<Bytes>>, StatusCode>
+where
+    Socket: AsyncRead + Unpin,
+{
+    loop {
+        if !buf.is_empty() {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(buf) {
+                Ok(httparse::Status::Complete(len)) => {
+                    let req = to_http_request(&req)?;
+                    buf.advance(len);
+                    let body = read_body(socket, buf, req.headers()).await?;
+                    return Ok(Some(req.map(|()| body)));
+                }
+                Ok(httparse::Status::Partial) => {}
+                Err(httparse::Error::TooManyHeaders) => {
+                    debug!("rejecting: too many headers");
+                    return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                }
+                Err(err) => {
+                    debug!("rejecting: failed to parse: {:?}", err);
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+            }
+            if buf.len() >= MAX_HEAD_LEN {
+                debug!("rejecting: request head too large");
+                return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            }
+        }
+
+        // A request that has started arriving is read to the end even during shutdown
+        let idle = buf.is_empty();
+        let read = select! {
+            res = tokio::time::timeout(KEEP_ALIVE_TIMEOUT, socket.read_buf(buf)) => res,
+            _ = shutdown.triggered(), if idle => {
+                trace!("closing idle connection for shutdown");
+                return Ok(None);
+            }
+        };
+        match read {
+            Ok(Ok(0)) => {
+                if !buf.is_empty() {
+                    debug!("closed with a partial request");
+                }
+                return Ok(None);
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                debug!("failed to read: {}", err);
+                return Ok(None);
+            }
+            Err(_) => {
+                trace!("closing idle connection");
+                return Ok(None);
+            }
+        }
+    }
+}
+
+async fn read_body<Socket>(
+    socket: &mut Socket,
+    buf: &mut BytesMut,
+    headers: &HeaderMap,
+) -> Result<Bytes, StatusCode>
+where
+    Socket: AsyncRead + Unpin,
+{
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        debug!("rejecting: unsupported transfer encoding");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    let len = match headers.get(header::CONTENT_LENGTH) {
+        Some(len) => len
+            .to_str()
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        None => 0,
+    };
+    if len > MAX_BODY_LEN {
+        debug!("rejecting: body too large");
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    while buf.len() < len {
+        match tokio::time::timeout(KEEP_ALIVE_TIMEOUT, socket.read_buf(buf)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return Err(StatusCode::BAD_REQUEST),
+            Ok(Ok(_)) => {}
+            Err(_) => return Err(StatusCode::REQUEST_TIMEOUT),
+        }
+    }
+    Ok(buf.split_to(len).freeze())
+}
+
+fn to_http_request(req: &httparse::Request) -> Result<http::Request<()>, StatusCode> {
+    let (Some(method), Some(path), Some(version)) = (req.method, req.path, req.version) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let version = match version {
+        0 => Version::HTTP_10,
+        1 => Version::HTTP_11,
+        _ => return Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+    };
+    let mut builder = http::Request::builder()
+        .method(method)
+        .uri(path)
+        .version(version);
+    for header in req.headers.iter() {
+        builder = builder.header(header.name, header.value);
+    }
+    builder.body(()).map_err(|err| {
+        debug!("rejecting: invalid request: {}", err);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+fn wants_keep_alive<B>(req: &http::Request<B>) -> bool {
+    if req.version() == Version::HTTP_10 {
+        has_token(req.headers(), header::CONNECTION, "keep-alive")
+    } else {
+        !has_token(req.headers(), header::CONNECTION, "close")
+    }
+}
+
+/// Checks if a comma-separated header such as `Connection` contains `token`, ignoring case.
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+fn is_valid_websocket_key(key: &[u8]) -> bool {
+    use base64::prelude::*;
+    BASE64_STANDARD
+        .decode(key)
+        .is_ok_and(|decoded| decoded.len() == 16)
+}
+
+pub(crate) fn text_response(status: StatusCode, body: impl Into<Bytes>) -> http::Response<Bytes> {
+    http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(body.into())
+        .unwrap()
+}
+
+/// A plain text response with the status's reason as the body.
+pub(crate) fn error_response(status: StatusCode) -> http::Response<Bytes> {
+    let body = status.canonical_reason().unwrap_or("Error");
+    text_response(status, Bytes::from_static(body.as_bytes()))
+}
+
+pub(crate) fn method_not_allowed(allow: &'static str) -> http::Response<Bytes> {
+    let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED);
+    response
+        .headers_mut()
+        .insert(header::ALLOW, allow.parse().unwrap());
+    response
+}
+
+async fn write_response<Socket>(
+    socket: &mut Socket,
+    mut response: http::Response<Bytes>,
+    head_only: bool,
+    keep_alive: bool,
+) -> std::io::Result<()>
+where
+    Socket: AsyncWrite + Unpin,
+{
+    let len = response.body().len();
+    let status = response.status();
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let headers = response.headers_mut();
+    if status != StatusCode::NOT_MODIFIED {
+        headers.insert(header::CONTENT_LENGTH, len.into());
+    }
+    headers.insert(header::CONNECTION, connection.parse().unwrap());
+
+    let mut buf = http_head(&response);
+    if !head_only {
+        buf.extend_from_slice(response.body());
+    }
+    socket.write_all(&buf).await?;
+    socket.flush().await
+}
+
+fn http_head<T>(response: &http::Response<T>) -> BytesMut {
     let mut buf = BytesMut::new();
     let _ = buf.write_str("HTTP/1.1 ");
     let _ = buf.write_str(response.status().as_str());
@@ -159,7 +510,7 @@ fn http_head(response: http::Response<()>) -> BytesMut {
     for (name, value) in response.headers() {
         let _ = buf.write_str(name.as_str());
         let _ = buf.write_str(": ");
-        let _ = buf.write_str(value.to_str().unwrap());
+        buf.extend_from_slice(value.as_bytes());
         let _ = buf.write_str("\r\n");
     }
     let _ = buf.write_str("\r\n");
@@ -174,3 +525,263 @@ fn derive_accept_key(key: &[u8]) -> String {
     hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
     BASE64_STANDARD.encode(hasher.finalize())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::connections::{Connections, Transport};
+    use crate::db::{AuditDb, DocDb, UserDb};
+    use crate::doc_manager::DocManager;
+    use crate::sessions::SessionStore;
+    use crate::state::authorizer::Authorizer;
+    use shrubbery_common::frame::{ServerInfo, ServerLimits};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::DuplexStream;
+
+    /// A multiplexer with empty databases in `dir` and no static files.
+    fn multiplexer(dir: &Path) -> HttpMultiplexer<DuplexStream> {
+        let config = Config::default();
+        let shutdown = Shutdown::new(Duration::from_secs(1));
+        let user_db = UserDb::open(dir.join("users"), &config.storage).unwrap();
+        let docs_db = DocDb::open(dir.join("docs"), &config.storage).unwrap();
+        let audit_db = AuditDb::open(dir.join("audit"), &config.storage).unwrap();
+        let metrics = Metrics::new();
+        let doc_manager = DocManager::new(
+            docs_db.clone(),
+            config.presence(),
+            shutdown.clone(),
+            metrics.clone(),
+        );
+        let server_info = ServerInfo {
+            version: "test".to_string(),
+            protocol_versions: vec!["shrub1".to_string()],
+            encodings: vec!["json".to_string()],
+            features: vec![],
+            limits: ServerLimits {
+                max_frame_length: 0,
+                outbound_queue: config.limits.outbound_queue,
+                presence_ttl_seconds: config.presence.ttl,
+                presence_interval_seconds: config.presence.interval,
+                resume_window_seconds: 0,
+            },
+        };
+        let handlers = Handlers::new(
+            Authorizer::new("root".to_string()),
+            docs_db.clone(),
+            audit_db.clone(),
+            doc_manager.clone(),
+            Connections::new(),
+            server_info,
+            metrics,
+        );
+        let health = HealthEndpoints::new(
+            handlers.clone(),
+            user_db.clone(),
+            docs_db,
+            audit_db,
+            shutdown.clone(),
+            None,
+        );
+        let processor = socket_processor::SocketProcessor::new(
+            handlers.clone(),
+            doc_manager,
+            user_db,
+            SessionStore::new(Duration::ZERO, shutdown.clone()),
+            config.outbound(),
+            shutdown,
+        );
+        HttpMultiplexer::new(
+            StaticAssets::embedded([]),
+            handlers,
+            processor,
+            health,
+            None,
+            true,
+        )
+    }
+
+    /// Sends `request` on a new connection, closes its write side and returns everything the
+    /// server writes before closing it.
+    async fn exchange(request: &[u8]) -> String {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "shrub-http-multiplexer-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let multiplexer = multiplexer(&dir);
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let origin = Origin::new(1, Transport::Http, None);
+            multiplexer.handle(server, origin).await
+        });
+
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        server.await.unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        String::from_utf8(response).unwrap()
+    }
+
+    fn status_line(response: &str) -> &str {
+        response.lines().next().unwrap_or_default()
+    }
+
+    fn has_header(response: &str, header: &str) -> bool {
+        let head = response.split("\r\n\r\n").next().unwrap();
+        head.lines()
+            .skip(1)
+            .any(|line| line.eq_ignore_ascii_case(header))
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_alive() {
+        let response = exchange(
+            b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.matches("HTTP/1.1 404 Not Found").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn parses_header_names_case_insensitively() {
+        let response =
+            exchange(b"GET /healthz HTTP/1.1\r\nhOsT: x\r\nCONNECTION: Close\r\n\r\n").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        assert!(response.ends_with("ok\n"));
+    }
+
+    #[tokio::test]
+    async fn limits_the_request_head() {
+        let filler = "a".repeat(MAX_HEAD_LEN);
+        let request = format!("GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n", filler);
+        let response = exchange(request.as_bytes()).await;
+        assert_eq!(
+            status_line(&response),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+
+        let filler = "a".repeat(MAX_HEAD_LEN - 100);
+        let request = format!(
+            "GET / HTTP/1.1\r\nX-Filler: {}\r\nConnection: close\r\n\r\n",
+            filler
+        );
+        let response = exchange(request.as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn limits_the_number_of_headers() {
+        let request = |count: usize| {
+            let mut request = "GET / HTTP/1.1\r\nConnection: close\r\n".to_string();
+            for i in 1..count {
+                let _ = write!(request, "X-Header-{}: {}\r\n", i, i);
+            }
+            request.push_str("\r\n");
+            request
+        };
+        let response = exchange(request(MAX_HEADERS).as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 404 Not Found");
+        let response = exchange(request(MAX_HEADERS + 1).as_bytes()).await;
+        assert_eq!(
+            status_line(&response),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_the_body() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_LEN + 1
+        );
+        let response = exchange(request.as_bytes()).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 413 Payload Too Large");
+
+        // A body at the limit is read in full before the request is routed
+        let mut request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            MAX_BODY_LEN
+        )
+        .into_bytes();
+        request.resize(request.len() + MAX_BODY_LEN, b'a');
+        let response = exchange(&request).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 405 Method Not Allowed");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_content_lengths() {
+        let response = exchange(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        // The connection closes before the body arrives
+        let response = exchange(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn rejects_transfer_encodings() {
+        let response = exchange(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 501 Not Implemented");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests() {
+        let response = exchange(b"NOT HTTP\r\n\r\n").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_methods_for_files() {
+        let response = exchange(b"DELETE /index.html HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 405 Method Not Allowed");
+        assert!(has_header(&response, "allow: GET, HEAD"));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_methods_for_websockets() {
+        let response = exchange(b"POST /socket HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 405 Method Not Allowed");
+        assert!(has_header(&response, "allow: GET"));
+    }
+
+    #[tokio::test]
+    async fn requires_a_websocket_upgrade() {
+        let response = exchange(b"GET /socket HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 426 Upgrade Required");
+        assert!(has_header(&response, "upgrade: websocket"));
+
+        let response = exchange(
+            b"GET /socket HTTP/1.1\r\nConnection: Upgrade, close\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 426 Upgrade Required");
+        assert!(has_header(&response, "sec-websocket-version: 13"));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_websocket_keys() {
+        let response = exchange(
+            b"GET /socket HTTP/1.1\r\nConnection: Upgrade, close\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n\r\n",
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn derives_the_websocket_accept_key() {
+        // The example from RFC 6455
+        assert_eq!(
+            derive_accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::config::{ListenerConfig, ListenerProtocol};
+use crate::connections::{connection_span, Connections, Origin, Transport};
+use crate::proto::health::HealthEndpoints;
+use crate::proto::http_multiplexer::{self, HttpMultiplexer};
+use crate::proto::metrics_endpoint::MetricsEndpoint;
+use crate::proto::sniffer::ProtocolSniffer;
+use crate::proto::socket_processor::SocketProcessor;
+use crate::shutdown::Shutdown;
+use crate::state::client_certs::ClientCertAuthorizer;
+use crate::tls::ReloadableTlsAcceptor;
+use crate::FramedConnection;
+use shrubbery_common::tls::TlsStream;
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tracing::{debug, info, warn, Instrument};
+
+/// Connections queued by the OS before they're accepted.
+const BACKLOG: i32 = 1024;
+
+/// How long to wait before accepting again after an error, such as running out of file
+/// descriptors, which would otherwise repeat immediately.
+pub(crate) const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// What a listener hands accepted connections to.
+#[derive(Clone)]
+pub struct Services {
+    pub connections: Connections,
+    pub shrub: SocketProcessor<FramedConnection>,
+    pub http: HttpMultiplexer<TcpStream>,
+    pub https: HttpMultiplexer<TlsStream<TcpStream>>,
+    pub unified: ProtocolSniffer,
+    pub metrics: MetricsEndpoint,
+    pub health: HealthEndpoints,
+    pub tls_acceptor: ReloadableTlsAcceptor,
+    pub client_certs: Option<ClientCertAuthorizer>,
+}
+
+/// A TCP port accepting connections for one of the protocols in [`ListenerProtocol`].
+pub struct Listener {
+    listener: TcpListener,
+    config: ListenerConfig,
+    services: Services,
+    shutdown: Shutdown,
+}
+
+impl Listener {
+    pub fn bind(
+        config: ListenerConfig,
+        services: Services,
+        shutdown: Shutdown,
+    ) -> io::Result<Self> {
+        let address = config.address;
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        if address.is_ipv6() {
+            socket.set_only_v6(config.ipv6_only)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(BACKLOG)?;
+        let listener = TcpListener::from_std(socket.into())?;
+        let config = ListenerConfig {
+            address: listener.local_addr()?,
+            ..config
+        };
+        Ok(Self {
+            listener,
+            config,
+            services,
+            shutdown,
+        })
+    }
+
+    /// The listener's config with the address it actually bound, which differs for port 0.
+    pub fn bound(&self) -> ListenerConfig {
+        self.config
+    }
+
+    /// Accepts connections until shutdown.
+    pub async fn serve(self) {
+        loop {
+            let (socket, peer) = select! {
+                res = self.listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Error accepting connection on {}: {}", self.config, err);
+                        select! {
+                            _ = tokio::time::sleep(ACCEPT_ERROR_DELAY) => continue,
+                            _ = self.shutdown.triggered() => break,
+                        }
+                    }
+                },
+                _ = self.shutdown.triggered() => break,
+            };
+            let id = self.services.connections.next_id();
+            let span = connection_span(id, self.config, Some(peer));
+            let services = self.services.clone();
+            let config = self.config;
+            let shutdown = self.shutdown.clone();
+            self.shutdown.spawn(
+                async move {
+                    debug!("Accepted connection");
+                    handle(socket, peer, id, config, services, shutdown).await;
+                }
+                .instrument(span),
+            );
+        }
+        info!("Stopped listening on {}", self.config);
+    }
+}
+
+async fn handle(
+    socket: TcpStream,
+    peer: SocketAddr,
+    id: u64,
+    config: ListenerConfig,
+    services: Services,
+    shutdown: Shutdown,
+) {
+    match (config.protocol, config.tls) {
+        (ListenerProtocol::Shrub, false) => {
+            let socket = match FramedConnection::accept_shrub(socket).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    info!("Connection error: {}", err);
+                    return;
+                }
+            };
+            let origin = Origin::new(id, Transport::Shrub, Some(peer));
+            services.shrub.accept(socket, origin).await;
+        }
+        (ListenerProtocol::Shrub, true) => {
+            let socket = match services.tls_acceptor.accept(socket).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    debug!("TLS handshake failed: {}", err);
+                    return;
+                }
+            };
+            let client_auth = services
+                .client_certs
+                .and_then(|authorizer| authorizer.authenticate(&socket.peer_certificate()?));
+            let socket = match FramedConnection::accept_shrub_tls(socket).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    info!("Connection error: {}", err);
+                    return;
+                }
+            };
+            let origin = Origin::new(id, Transport::ShrubTls, Some(peer));
+            match client_auth {
+                Some(entry) => {
+                    services
+                        .shrub
+                        .accept_authenticated(socket, entry, origin)
+                        .await
+                }
+                None => services.shrub.accept(socket, origin).await,
+            }
+        }
+        (ListenerProtocol::Http, false) => {
+            let origin = Origin::new(id, Transport::Http, Some(peer));
+            services.http.handle(socket, origin).await;
+        }
+        (ListenerProtocol::Http, true) => {
+            let socket = match services.tls_acceptor.accept(socket).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    debug!("TLS handshake failed: {}", err);
+                    return;
+                }
+            };
+            let client_auth = services
+                .client_certs
+                .and_then(|authorizer| authorizer.authenticate(&socket.peer_certificate()?));
+            let origin = Origin::new(id, Transport::Https, Some(peer));
+            services
+                .https
+                .handle_authenticated(socket, client_auth, origin)
+                .await;
+        }
+        (ListenerProtocol::Unified, _) => services.unified.handle(socket, id).await,
+        (ListenerProtocol::Metrics, _) => {
+            http_multiplexer::serve_monitoring(
+                socket,
+                &services.metrics,
+                &services.health,
+                &shutdown,
+            )
+            .await
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::db::{DocDb, UserDb};
+use crate::doc_manager::DocManager;
+use crate::handlers::Handlers;
+use crate::metrics::Encoder;
+use crate::proto::http_multiplexer::{error_response, method_not_allowed, text_response};
+use bytes::Bytes;
+use http::{header, Method, StatusCode};
+use tracing::debug;
+
+/// The RocksDB properties exported for each database.
+const DB_PROPERTIES: &[&str] = &[
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.block-cache-usage",
+    "rocksdb.num-running-compactions",
+    "rocksdb.num-running-flushes",
+];
+
+/// `GET /metrics` in the Prometheus text format.
+#[derive(Clone, Debug)]
+pub struct MetricsEndpoint {
+    handlers: Handlers,
+    doc_manager: DocManager,
+    user_db: UserDb,
+    docs_db: DocDb,
+    token: Option<String>,
+}
+
+impl MetricsEndpoint {
+    /// If `token` is set, requests must send it with `Authorization: Bearer <token>`.
+    pub fn new(
+        handlers: Handlers,
+        doc_manager: DocManager,
+        user_db: UserDb,
+        docs_db: DocDb,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            handlers,
+            doc_manager,
+            user_db,
+            docs_db,
+            token,
+        }
+    }
+
+    pub fn respond(&self, req: &http::Request<Bytes>) -> http::Response<Bytes> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return method_not_allowed("GET, HEAD");
+        }
+        if !self.is_authorized(req) {
+            debug!("rejecting metrics request without the metrics token");
+            let mut response = error_response(StatusCode::UNAUTHORIZED);
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            return response;
+        }
+        let mut response = text_response(StatusCode::OK, self.render());
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4".parse().unwrap(),
+        );
+        response
+    }
+
+    fn is_authorized(&self, req: &http::Request<Bytes>) -> bool {
+        let Some(expected) = &self.token else {
+            return true;
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .is_some_and(|(_, token)| {
+                constant_time_eq(token.trim().as_bytes(), expected.as_bytes())
+            })
+    }
+
+    fn render(&self) -> String {
+        let mut out = Encoder::new();
+        self.handlers.metrics().encode(&mut out);
+
+        out.family("shrub_sessions", "gauge", "Authenticated frame connections");
+        let sessions = self.handlers.connections().len();
+        out.sample("shrub_sessions", &[], sessions);
+
+        let docs = self.doc_manager.open_docs();
+        out.family("shrub_open_docs", "gauge", "Docs with a running worker");
+        out.sample("shrub_open_docs", &[], docs.len());
+        // Aggregated rather than labelled by doc, which would add a series for every doc ever
+        // opened. ListOpenDocs has the per-doc numbers.
+        out.family(
+            "shrub_doc_clients",
+            "gauge",
+            "Connections with a doc open, summed over open docs",
+        );
+        let clients = docs.iter().map(|info| info.clients).sum::<usize>();
+        out.sample("shrub_doc_clients", &[], clients);
+        out.family(
+            "shrub_doc_clients_max",
+            "gauge",
+            "Connections with the busiest doc open",
+        );
+        let max = docs.iter().map(|info| info.clients).max().unwrap_or(0);
+        out.sample("shrub_doc_clients_max", &[], max);
+
+        out.family(
+            "shrub_rocksdb_property",
+            "gauge",
+            "Integer RocksDB properties by database",
+        );
+        encode_db_properties(&mut out, "users", |name| self.user_db.property(name));
+        encode_db_properties(&mut out, "docs", |name| self.docs_db.property(name));
+
+        out.finish()
+    }
+}
+
+fn encode_db_properties(
+    out: &mut Encoder,
+    db: &str,
+    property: impl Fn(&str) -> eyre::Result<Option<u64>>,
+) {
+    for &name in DB_PROPERTIES {
+        match property(name) {
+            Ok(Some(value)) => out.sample(
+                "shrub_rocksdb_property",
+                &[("db", db), ("property", name)],
+                value,
+            ),
+            Ok(None) => {}
+            Err(err) => debug!("Failed to read {} for the {} db: {}", name, db, err),
+        }
+    }
+}
+
+/// Compares every byte rather than stopping at the first difference, so the time taken doesn't
+/// reveal how much of a guessed token is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -1,3 +1,14 @@
+#[cfg(unix)]
+pub mod admin_socket;
+pub mod error;
 mod framed_websocket;
+pub mod health;
 pub mod http_multiplexer;
+pub mod listener;
+pub mod metrics_endpoint;
+pub mod outbound;
+pub mod rest_api;
+pub mod sniffer;
 pub mod socket_processor;
+pub mod static_assets;
+mod websocket_deflate;
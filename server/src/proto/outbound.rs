@@ -0,0 +1,186 @@
+//! The bounded queue of frames waiting to be written to a connection.
+//!
+//! Frames are queued without waiting for the socket, so a client that stops reading can't stall
+//! its connection's processing or the doc workers fanning out presence to it.
+
+use crate::Frame;
+use futures::{Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use shrubbery_common::frame::QueueInfo;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundConfig {
+    /// How many frames may be waiting to be written before the queue overflows. At least 1.
+    pub capacity: usize,
+    /// How long writing a single frame may take before the connection is closed.
+    pub write_timeout: Duration,
+    pub overflow: OverflowPolicy,
+}
+
+/// What to do when a frame doesn't fit in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Drop presence frames, which the next update supersedes, and close the connection if
+    /// any other frame doesn't fit.
+    DropPresence,
+    /// Close the connection as soon as any frame doesn't fit.
+    Disconnect,
+}
+
+/// Counters for one connection's queue, shared with the connection registry.
+#[derive(Debug)]
+pub struct QueueStats {
+    capacity: usize,
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    sent: AtomicU64,
+    dropped_presence: AtomicU64,
+}
+
+/// The sending half of a connection's queue.
+///
+/// Pushing never waits. Once a frame that must be delivered doesn't fit the queue is marked
+/// overflowed, and the connection should be closed.
+pub struct Outbound {
+    tx: mpsc::Sender<Frame>,
+    stats: Arc<QueueStats>,
+    overflow: OverflowPolicy,
+    overflowed: bool,
+}
+
+impl Outbound {
+    /// Creates a queue and the future that writes it to `sink` until every sender is dropped.
+    pub fn new<K>(
+        config: &OutboundConfig,
+        sink: K,
+    ) -> (Self, impl std::future::Future<Output = std::io::Result<()>>)
+    where
+        K: Sink<Frame, Error = std::io::Error> + Unpin,
+    {
+        let (tx, rx) = mpsc::channel(config.capacity);
+        let stats = Arc::new(QueueStats::new(config.capacity));
+        let writer = write_queue(sink, rx, stats.clone(), config.write_timeout);
+        let outbound = Self {
+            tx,
+            stats,
+            overflow: config.overflow,
+            overflowed: false,
+        };
+        (outbound, writer)
+    }
+
+    pub fn stats(&self) -> &Arc<QueueStats> {
+        &self.stats
+    }
+
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Queues a frame the client must receive, such as a reply.
+    pub fn push(&mut self, frame: Frame) {
+        if self.overflowed {
+            return;
+        }
+        match self.tx.try_send(frame) {
+            Ok(()) => self.stats.queued(),
+            Err(TrySendError::Full(_)) => {
+                debug!("Outbound queue overflowed");
+                self.overflowed = true;
+            }
+            // The writer has stopped, which the connection notices when it polls the writer
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Queues a presence frame, returning whether it was queued rather than dropped.
+    pub fn push_presence(&mut self, frame: Frame) -> bool {
+        if self.overflow == OverflowPolicy::DropPresence && self.tx.capacity() == 0 {
+            self.stats.dropped_presence.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.push(frame);
+        !self.overflowed
+    }
+}
+
+impl QueueStats {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            depth: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0),
+            sent: AtomicU64::new(0),
+            dropped_presence: AtomicU64::new(0),
+        }
+    }
+
+    fn queued(&self) {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> QueueInfo {
+        QueueInfo {
+            capacity: self.capacity,
+            depth: self.depth.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped_presence: self.dropped_presence.load(Ordering::Relaxed),
+        }
+    }
+}
+
+async fn write_queue<K>(
+    mut sink: K,
+    mut rx: mpsc::Receiver<Frame>,
+    stats: Arc<QueueStats>,
+    write_timeout: Duration,
+) -> std::io::Result<()>
+where
+    K: Sink<Frame, Error = std::io::Error> + Unpin,
+{
+    let timed_out = || std::io::Error::new(std::io::ErrorKind::TimedOut, "write timed out");
+    while let Some(frame) = rx.recv().await {
+        stats.depth.fetch_sub(1, Ordering::Relaxed);
+        tokio::time::timeout(write_timeout, sink.send(frame))
+            .await
+            .map_err(|_| timed_out())??;
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+    tokio::time::timeout(write_timeout, sink.close())
+        .await
+        .map_err(|_| timed_out())?
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-presence" => Ok(OverflowPolicy::DropPresence),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(eyre::eyre!(
+                "expected an overflow policy of drop-presence or disconnect"
+            )),
+        }
+    }
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverflowPolicy::DropPresence => write!(f, "drop-presence"),
+            OverflowPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
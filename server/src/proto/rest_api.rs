@@ -0,0 +1,202 @@
+use crate::handlers::{self, Handlers};
+use crate::proto::static_assets::percent_decode;
+use crate::state::authorizer;
+use bytes::Bytes;
+use http::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use shrubbery_common::DocId;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+/// JSON routes under `/api/`, authenticated with `Authorization: Bearer <token>`.
+///
+/// Request and response bodies mirror the corresponding frames.
+#[derive(Clone, Debug)]
+pub struct RestApi {
+    handlers: Handlers,
+}
+
+#[derive(Debug, Deserialize)]
+struct MintTokenRequest {
+    user: String,
+    info: Option<serde_json::Value>,
+    lifetime_seconds: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct MintTokenResponse {
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+enum Route<'a> {
+    Tokens,
+    UserTokens(&'a str),
+    Docs,
+    Doc(&'a str),
+    Connections,
+}
+
+impl RestApi {
+    pub fn new(handlers: Handlers) -> Self {
+        Self { handlers }
+    }
+
+    /// Requests without an `Authorization` header act as `client_auth` if the connection was
+    /// authenticated by a client certificate. `peer` is recorded in the audit log with admin
+    /// actions.
+    pub async fn respond(
+        &self,
+        req: &http::Request<Bytes>,
+        client_auth: Option<&authorizer::Entry>,
+        peer: Option<SocketAddr>,
+    ) -> http::Response<Bytes> {
+        let path = req.uri().path().trim_start_matches("/api/");
+        let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+        let route = match segments.as_slice() {
+            ["tokens"] => Route::Tokens,
+            ["users", user, "tokens"] => Route::UserTokens(user),
+            ["docs"] => Route::Docs,
+            ["docs", doc] => Route::Doc(doc),
+            ["connections"] => Route::Connections,
+            _ => return error_response(StatusCode::NOT_FOUND, "not found".to_string()),
+        };
+
+        let allow = match route {
+            Route::Tokens | Route::Docs => "POST",
+            Route::UserTokens(_) => "DELETE",
+            Route::Doc(_) | Route::Connections => "GET, HEAD",
+        };
+        if !allow
+            .split(", ")
+            .any(|method| method == req.method().as_str())
+        {
+            let mut response = error_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed".to_string(),
+            );
+            response
+                .headers_mut()
+                .insert(header::ALLOW, allow.parse().unwrap());
+            return response;
+        }
+
+        match self.handle(req, route, client_auth, peer).await {
+            Ok(response) => response,
+            Err(err) => handler_error_response(err),
+        }
+    }
+
+    async fn handle(
+        &self,
+        req: &http::Request<Bytes>,
+        route: Route<'_>,
+        client_auth: Option<&authorizer::Entry>,
+        peer: Option<SocketAddr>,
+    ) -> Result<http::Response<Bytes>, handlers::Error> {
+        let auth = self.authenticate(req, client_auth)?;
+        match route {
+            Route::Tokens => {
+                let body: MintTokenRequest = parse_body(req)?;
+                let token = self
+                    .handlers
+                    .mint_token(&auth, peer, body.user, body.info, body.lifetime_seconds)
+                    .await?;
+                Ok(json_response(
+                    StatusCode::CREATED,
+                    &MintTokenResponse { token },
+                ))
+            }
+            Route::UserTokens(user) => {
+                let user = percent_decode(user)
+                    .ok_or_else(|| handlers::Error::BadRequest("invalid user".to_string()))?;
+                self.handlers
+                    .revoke_tokens_for_user(&auth, peer, user)
+                    .await?;
+                Ok(http::Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Bytes::new())
+                    .unwrap())
+            }
+            Route::Docs => {
+                let info = self.handlers.create_doc(&auth)?;
+                Ok(json_response(StatusCode::CREATED, &info))
+            }
+            Route::Doc(doc) => {
+                let doc = DocId::from_str(doc)
+                    .map_err(|err| handlers::Error::BadRequest(err.to_string()))?;
+                let info = self.handlers.get_doc(&auth, doc)?;
+                Ok(json_response(StatusCode::OK, &info))
+            }
+            Route::Connections => {
+                let connections = self.handlers.list_connections(&auth)?;
+                Ok(json_response(StatusCode::OK, &connections))
+            }
+        }
+    }
+
+    fn authenticate(
+        &self,
+        req: &http::Request<Bytes>,
+        client_auth: Option<&authorizer::Entry>,
+    ) -> Result<authorizer::Entry, handlers::Error> {
+        let Some(value) = req.headers().get(header::AUTHORIZATION) else {
+            return client_auth.cloned().ok_or(handlers::Error::Unauthorized);
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or(handlers::Error::Unauthorized)?;
+        self.handlers.authenticate(token)
+    }
+}
+
+fn parse_body<T>(req: &http::Request<Bytes>) -> Result<T, handlers::Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    serde_json::from_slice(req.body())
+        .map_err(|err| handlers::Error::BadRequest(format!("invalid body: {}", err)))
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> http::Response<Bytes> {
+    let body = serde_json::to_vec(body).expect("response body is serializable");
+    http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+fn handler_error_response(err: handlers::Error) -> http::Response<Bytes> {
+    let status = match &err {
+        handlers::Error::Unauthorized => StatusCode::UNAUTHORIZED,
+        handlers::Error::Forbidden => StatusCode::FORBIDDEN,
+        handlers::Error::NotFound => StatusCode::NOT_FOUND,
+        handlers::Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+        handlers::Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    match &err {
+        handlers::Error::Internal(report) => warn!("Error handling API request: {:#}", report),
+        _ => debug!("API request failed: {}", err),
+    }
+    let mut response = error_response(status, err.to_string());
+    if status == StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    }
+    response
+}
+
+fn error_response(status: StatusCode, error: String) -> http::Response<Bytes> {
+    json_response(status, &ErrorBody { error })
+}
@@ -0,0 +1,379 @@
+use crate::connections::{Origin, Transport};
+use crate::proto::http_multiplexer::HttpMultiplexer;
+use crate::proto::socket_processor::SocketProcessor;
+use crate::state::client_certs::ClientCertAuthorizer;
+use crate::tls::ReloadableTlsAcceptor;
+use bytes::{Buf, BytesMut};
+use shrubbery_common::codec::ShrubCodec;
+use shrubbery_common::tls;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Framed};
+use tracing::{debug, trace};
+
+/// How long a client may take to send enough bytes to tell what protocol it speaks.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SHRUB_HEADER: &[u8] = b"shrub1\n";
+
+/// The record type of a TLS handshake, which a ClientHello starts with.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+/// Serves every protocol on a single port by looking at the first bytes of each connection.
+///
+/// `shrub1\n` goes to the shrub codec, a TLS ClientHello to the TLS acceptor (after which the
+/// decrypted stream is sniffed again) and an HTTP request line to the HTTP multiplexer. If the
+/// client negotiated `http/1.1` with ALPN the connection is treated as HTTP without sniffing.
+pub struct ProtocolSniffer {
+    tls_acceptor: ReloadableTlsAcceptor,
+    client_certs: Option<ClientCertAuthorizer>,
+    http: HttpMultiplexer<Sniffed>,
+    shrub: SocketProcessor<Framed<Sniffed, ShrubCodec>>,
+}
+
+impl Clone for ProtocolSniffer {
+    fn clone(&self) -> Self {
+        Self {
+            tls_acceptor: self.tls_acceptor.clone(),
+            client_certs: self.client_certs.clone(),
+            http: self.http.clone(),
+            shrub: self.shrub.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Shrub,
+    Tls,
+    Http,
+}
+
+impl ProtocolSniffer {
+    pub fn new(
+        tls_acceptor: ReloadableTlsAcceptor,
+        client_certs: Option<ClientCertAuthorizer>,
+        http: HttpMultiplexer<Sniffed>,
+        shrub: SocketProcessor<Framed<Sniffed, ShrubCodec>>,
+    ) -> Self {
+        Self {
+            tls_acceptor,
+            client_certs,
+            http,
+            shrub,
+        }
+    }
+
+    /// Handles the connection numbered `id` by [`Connections::next_id`].
+    ///
+    /// [`Connections::next_id`]: crate::connections::Connections::next_id
+    pub async fn handle(&self, socket: TcpStream, id: u64) {
+        let peer = socket.peer_addr().ok();
+        let mut socket = Sniffed::new(socket);
+        let protocol = match socket.sniff().await {
+            Ok(protocol) => protocol,
+            Err(err) => {
+                debug!("Failed to sniff protocol: {}", err);
+                return;
+            }
+        };
+        trace!("sniffed {:?}", protocol);
+        if protocol != Protocol::Tls {
+            self.dispatch(socket, protocol, false, None, id, peer).await;
+            return;
+        }
+
+        let socket = match self.tls_acceptor.accept(socket).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                debug!("TLS handshake failed: {}", err);
+                return;
+            }
+        };
+        let client_auth = self
+            .client_certs
+            .as_ref()
+            .and_then(|authorizer| authorizer.authenticate(&socket.peer_certificate()?));
+        let alpn_http = socket.alpn_protocol() == Some(tls::ALPN_HTTP);
+
+        let mut socket = Sniffed::new(socket);
+        let protocol = if alpn_http {
+            Protocol::Http
+        } else {
+            match socket.sniff().await {
+                Ok(Protocol::Tls) => {
+                    debug!("Rejecting TLS nested in TLS");
+                    return;
+                }
+                Ok(protocol) => protocol,
+                Err(err) => {
+                    debug!("Failed to sniff protocol inside TLS: {}", err);
+                    return;
+                }
+            }
+        };
+        trace!("sniffed {:?} inside TLS", protocol);
+        self.dispatch(socket, protocol, true, client_auth, id, peer)
+            .await;
+    }
+
+    async fn dispatch(
+        &self,
+        mut socket: Sniffed,
+        protocol: Protocol,
+        secure: bool,
+        client_auth: Option<crate::state::authorizer::Entry>,
+        id: u64,
+        peer: Option<SocketAddr>,
+    ) {
+        let transport = match (protocol, secure) {
+            (Protocol::Shrub, false) => Transport::Shrub,
+            (Protocol::Shrub, true) => Transport::ShrubTls,
+            (_, false) => Transport::Http,
+            (_, true) => Transport::Https,
+        };
+        let origin = Origin::new(id, transport, peer);
+        match protocol {
+            Protocol::Http => {
+                self.http
+                    .handle_authenticated(socket, client_auth, origin)
+                    .await
+            }
+            Protocol::Shrub => {
+                socket.prefix.advance(SHRUB_HEADER.len());
+                let socket = ShrubCodec::new().framed(socket);
+                match client_auth {
+                    Some(entry) => self.shrub.accept_authenticated(socket, entry, origin).await,
+                    None => self.shrub.accept(socket, origin).await,
+                }
+            }
+            Protocol::Tls => unreachable!("TLS is handled before dispatching"),
+        }
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Io for T {}
+
+/// A stream that replays the bytes read while sniffing before reading from the inner stream.
+pub struct Sniffed {
+    prefix: BytesMut,
+    inner: Box<dyn Io>,
+}
+
+impl Sniffed {
+    fn new<S>(inner: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    {
+        Self {
+            prefix: BytesMut::new(),
+            inner: Box::new(inner),
+        }
+    }
+
+    /// Reads until the protocol can be told apart, keeping the bytes read to be replayed.
+    async fn sniff(&mut self) -> io::Result<Protocol> {
+        tokio::time::timeout(SNIFF_TIMEOUT, async {
+            loop {
+                if let Some(protocol) = detect(&self.prefix)? {
+                    return Ok(protocol);
+                }
+                if self.inner.read_buf(&mut self.prefix).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+}
+
+/// Returns the protocol the bytes start with, or `None` if more are needed to tell.
+fn detect(buf: &[u8]) -> io::Result<Option<Protocol>> {
+    let Some(&first) = buf.first() else {
+        return Ok(None);
+    };
+    if first == TLS_HANDSHAKE {
+        return Ok(Some(Protocol::Tls));
+    }
+
+    let mut incomplete = false;
+    let candidates = std::iter::once((SHRUB_HEADER, Protocol::Shrub))
+        .chain(HTTP_METHODS.iter().map(|method| (*method, Protocol::Http)));
+    for (prefix, protocol) in candidates {
+        if buf.starts_with(prefix) {
+            return Ok(Some(protocol));
+        }
+        if prefix.starts_with(buf) {
+            incomplete = true;
+        }
+    }
+    if incomplete {
+        Ok(None)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unrecognized protocol",
+        ))
+    }
+}
+
+impl AsyncRead for Sniffed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..len]);
+            self.prefix.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Sniffed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn detected(buf: &[u8]) -> Option<Protocol> {
+        detect(buf).unwrap()
+    }
+
+    #[test]
+    fn detects_complete_prefixes() {
+        assert_eq!(detected(b"shrub1\n"), Some(Protocol::Shrub));
+        assert_eq!(detected(b"shrub1\n{\"id\":-1"), Some(Protocol::Shrub));
+        assert_eq!(detected(b"GET / HTTP/1.1\r\n"), Some(Protocol::Http));
+        assert_eq!(detected(b"OPTIONS * HTTP/1.1"), Some(Protocol::Http));
+        assert_eq!(detected(b"DELETE "), Some(Protocol::Http));
+    }
+
+    #[test]
+    fn waits_for_partial_prefixes() {
+        assert_eq!(detected(b""), None);
+        assert_eq!(detected(b"G"), None);
+        assert_eq!(detected(b"GE"), None);
+        assert_eq!(detected(b"GET"), None);
+        assert_eq!(detected(b"shr"), None);
+        assert_eq!(detected(b"shrub1"), None);
+        // Could be POST, PUT or PATCH
+        assert_eq!(detected(b"P"), None);
+    }
+
+    #[test]
+    fn detects_tls_from_the_first_byte() {
+        assert_eq!(detected(&[TLS_HANDSHAKE]), Some(Protocol::Tls));
+        assert_eq!(detected(&[TLS_HANDSHAKE, 0x03, 0x01]), Some(Protocol::Tls));
+    }
+
+    #[test]
+    fn rejects_unknown_bytes() {
+        for buf in [
+            &b"\x00"[..],
+            b"SSH-2.0-OpenSSH",
+            b"get / HTTP/1.1",
+            b"GET\t/",
+            b"shrub2\n",
+            b"PRI * HTTP/2.0",
+            &[0x15, 0x03, 0x01],
+        ] {
+            let err = detect(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_sniffed_prefix() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut socket = Sniffed::new(server);
+        client.write_all(b"GE").await.unwrap();
+        let sniff = tokio::spawn(async move {
+            let protocol = socket.sniff().await.unwrap();
+            (socket, protocol)
+        });
+        tokio::task::yield_now().await;
+        client.write_all(b"T / HTTP/1.1\r\n\r\n").await.unwrap();
+        let (mut socket, protocol) = sniff.await.unwrap();
+        assert_eq!(protocol, Protocol::Http);
+
+        client.write_all(b"rest").await.unwrap();
+        drop(client);
+        let mut read = Vec::new();
+        socket.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"GET / HTTP/1.1\r\n\r\nrest");
+    }
+
+    #[tokio::test]
+    async fn replays_the_prefix_into_small_buffers() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut socket = Sniffed::new(server);
+        client.write_all(b"shrub1\n{}").await.unwrap();
+        assert_eq!(socket.sniff().await.unwrap(), Protocol::Shrub);
+
+        let mut buf = [0; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"shru");
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"b1\n{");
+    }
+
+    #[tokio::test]
+    async fn fails_on_eof_before_the_protocol_is_known() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut socket = Sniffed::new(server);
+        client.write_all(b"shr").await.unwrap();
+        drop(client);
+        let err = socket.sniff().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn fails_on_unknown_protocols() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut socket = Sniffed::new(server);
+        client.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+        let err = socket.sniff().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,35 +1,64 @@
+use crate::connections::{Connections, Kick, Origin, Registration};
 use crate::db::UserDb;
-use crate::doc_manager::{DocHandle, DocManager};
+use crate::doc_manager::DocManager;
+use crate::handlers::Handlers;
+use crate::metrics::{AuthFailure, PresenceDrop};
+use crate::proto::error;
+use crate::proto::outbound::{Outbound, OutboundConfig};
+use crate::sessions::{Session, SessionStore, TakeoverRequest};
+use crate::shutdown::Shutdown;
 use crate::state::authorizer;
-use crate::state::authorizer::Authorizer;
 use crate::Frame;
 use eyre::eyre;
-use futures::{SinkExt, StreamExt};
-use shrubbery_common::frame::{FrameType, PresenceFrame};
-use shrubbery_common::DocId;
-use std::collections::HashMap;
+use futures::future::FusedFuture;
+use futures::stream::SplitStream;
+use futures::{FutureExt, SinkExt, StreamExt};
+use shrubbery_common::frame::{DocInfo, FrameType};
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Instant;
 use tokio::select;
-use tokio::sync::mpsc;
-use tracing::{info, trace, warn};
+use tokio::sync::oneshot;
+use tracing::{debug, info, trace, warn, Span};
 
 pub struct SocketProcessor<S> {
-    authorizer: Authorizer,
+    handlers: Handlers,
     doc_manager: DocManager,
     user_db: UserDb,
+    sessions: SessionStore,
+    outbound: OutboundConfig,
+    shutdown: Shutdown,
     _socket: PhantomData<S>,
 }
 
 impl<S> SocketProcessor<S> {
-    pub fn new(authorizer: Authorizer, doc_manager: DocManager, user_db: UserDb) -> Self {
+    pub fn new(
+        handlers: Handlers,
+        doc_manager: DocManager,
+        user_db: UserDb,
+        sessions: SessionStore,
+        outbound: OutboundConfig,
+        shutdown: Shutdown,
+    ) -> Self {
         Self {
-            authorizer,
+            handlers,
             doc_manager,
             user_db,
+            sessions,
+            outbound,
+            shutdown,
             _socket: PhantomData,
         }
     }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    pub fn connections(&self) -> &Connections {
+        self.handlers.connections()
+    }
 }
 
 impl<S> SocketProcessor<S>
@@ -38,14 +67,28 @@ where
         + futures::Sink<Frame, Error = std::io::Error>
         + Unpin,
 {
-    pub async fn accept(&self, socket: S) {
-        let res = State::accept(
-            socket,
-            self.authorizer.clone(),
-            self.user_db.clone(),
-            self.doc_manager.clone(),
-        )
-        .await;
+    /// Processes a socket until it closes. Its logs go to the [`connection_span`] the caller is
+    /// in, with the transport recorded here.
+    ///
+    /// [`connection_span`]: crate::connections::connection_span
+    pub async fn accept(&self, socket: S, origin: Origin) {
+        let _connection = self.handlers.metrics().connection(origin.transport);
+        Span::current().record("transport", origin.transport.label());
+        let res = State::accept(socket, self.context(), origin).await;
+
+        if let Err(err) = res {
+            warn!("Error processing socket: {}", err);
+        }
+    }
+
+    /// Processes a socket whose peer was authenticated out of band, so no Authenticate frame is
+    /// expected.
+    pub async fn accept_authenticated(&self, socket: S, entry: authorizer::Entry, origin: Origin) {
+        let _connection = self.handlers.metrics().connection(origin.transport);
+        Span::current().record("transport", origin.transport.label());
+        info!("Authenticated as {}", &entry.user);
+        let session = Session::new(entry, 1);
+        let res = State::start(socket, self.context(), session, origin).await;
 
         if let Err(err) = res {
             warn!("Error processing socket: {}", err);
@@ -53,26 +96,76 @@ where
     }
 }
 
+impl<S> SocketProcessor<S> {
+    fn context(&self) -> Context {
+        Context {
+            handlers: self.handlers.clone(),
+            user_db: self.user_db.clone(),
+            doc_manager: self.doc_manager.clone(),
+            sessions: self.sessions.clone(),
+            outbound: self.outbound,
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
 impl<S> Clone for SocketProcessor<S> {
     fn clone(&self) -> Self {
         Self::new(
-            self.authorizer.clone(),
+            self.handlers.clone(),
             self.doc_manager.clone(),
             self.user_db.clone(),
+            self.sessions.clone(),
+            self.outbound,
+            self.shutdown.clone(),
         )
     }
 }
 
+/// Shared services a connection uses.
+struct Context {
+    handlers: Handlers,
+    user_db: UserDb,
+    doc_manager: DocManager,
+    sessions: SessionStore,
+    outbound: OutboundConfig,
+    shutdown: Shutdown,
+}
+
 struct State<S> {
-    socket: S,
-    authorizer: Authorizer,
+    frames: SplitStream<S>,
+    outbound: Outbound,
+    handlers: Handlers,
     user_db: UserDb,
     doc_manager: DocManager,
-    open: HashMap<DocId, DocHandle>,
-    presence_tx: mpsc::Sender<Vec<PresenceFrame>>,
-    presence_rx: mpsc::Receiver<Vec<PresenceFrame>>,
-    auth: authorizer::Entry,
-    next_frame_id: i32,
+    sessions: SessionStore,
+    shutdown: Shutdown,
+    session: Session,
+    resume_token: Option<String>,
+    takeover_rx: Option<oneshot::Receiver<TakeoverRequest>>,
+    registration: Registration,
+    /// Recorded in the audit log with admin actions
+    peer: Option<SocketAddr>,
+}
+
+/// Why a connection stopped being processed.
+enum Exit {
+    Closed,
+    Shutdown,
+    /// An admin kicked the connection, so its session can't be resumed
+    Kicked,
+    TakenOver(TakeoverRequest),
+}
+
+/// Resolves when another connection asks to take over the session.
+async fn recv_takeover(rx: &mut Option<oneshot::Receiver<TakeoverRequest>>) -> TakeoverRequest {
+    if let Some(inner) = rx.as_mut() {
+        if let Ok(reply_tx) = inner.await {
+            return reply_tx;
+        }
+        *rx = None;
+    }
+    std::future::pending().await
 }
 
 impl<S> State<S>
@@ -81,138 +174,271 @@ where
         + futures::Sink<Frame, Error = std::io::Error>
         + Unpin,
 {
-    async fn accept(
-        mut socket: S,
-        authorizer: Authorizer,
-        user_db: UserDb,
-        doc_manager: DocManager,
-    ) -> eyre::Result<()> {
+    async fn accept(mut socket: S, ctx: Context, origin: Origin) -> eyre::Result<()> {
         trace!("Processing connection");
-        let Some(frame) = socket.next().await else {
+        let frame = select! {
+            frame = socket.next() => frame,
+            _ = ctx.shutdown.triggered() => {
+                ctx.handlers.metrics().frame_sent("shutdown");
+                Self::send_shutdown(&mut socket, &ctx.shutdown, 1).await?;
+                return Ok(());
+            }
+        };
+        let Some(frame) = frame else {
             return Err(eyre!("disconnected"));
         };
         let frame = frame?;
         trace!("got frame at authenticate stage: {:?}", frame);
 
-        let res = match frame.frame {
-            FrameType::Authenticate { token } => {
-                if let Some(user) = authorizer.authenticate(&token) {
-                    Ok(user)
-                } else {
-                    Err(eyre!("invalid token"))
+        let metrics = ctx.handlers.metrics();
+        metrics.frame_received(frame.frame.name());
+        let session = match frame.frame {
+            FrameType::Authenticate { token } => ctx
+                .handlers
+                .authenticate(&token)
+                .map(|entry| {
+                    info!("Authenticated as {}", &entry.user);
+                    Session::new(entry, 2)
+                })
+                .map_err(error::Error::from),
+            FrameType::Resume { token } => match ctx.sessions.resume(&token).await {
+                Some(mut session) => {
+                    info!("Resumed session for {}", &session.auth.user);
+                    session.next_frame_id += 1;
+                    Ok(session)
                 }
-            }
-            _ => return Err(eyre::eyre!("Expected Authenticate frame")),
+                None => {
+                    metrics.auth_failed(AuthFailure::Resume);
+                    Err(error::Error::CannotResume)
+                }
+            },
+            FrameType::Malformed { error } => Err(error::Error::InvalidFrame(error)),
+            FrameType::UnknownFrame => Err(error::Error::UnknownFrame),
+            _ => Err(error::Error::UnexpectedFrame(
+                "expected authenticate or resume".to_string(),
+            )),
         };
-        let entry = match res {
-            Ok(role) => role,
+        let session = match session {
+            Ok(session) => session,
             Err(err) => {
-                let message = err.to_string();
-                let _ = socket
-                    .send(Frame::new_reply(
-                        1,
-                        frame.id,
-                        FrameType::Error { error: message },
-                    ))
-                    .await;
-                return Err(err);
+                let reply = err.to_frame();
+                metrics.frame_sent(reply.name());
+                let _ = socket.send(Frame::new_reply(1, frame.id, reply)).await;
+                return Err(err.into());
             }
         };
-        info!("Authenticated as {}", &entry.user);
+        metrics.frame_sent(FrameType::Ok.name());
         socket
-            .send(Frame::new_reply(1, frame.id, FrameType::Ok))
+            .send(Frame::new_reply(
+                session.next_frame_id - 1,
+                frame.id,
+                FrameType::Ok,
+            ))
             .await?;
 
-        let (presence_tx, presence_rx) = mpsc::channel(12);
+        Self::start(socket, ctx, session, origin).await
+    }
+
+    async fn start(socket: S, ctx: Context, session: Session, origin: Origin) -> eyre::Result<()> {
+        let (sink, frames) = socket.split();
+        let (outbound, writer) = Outbound::new(&ctx.outbound, sink);
+        let writer = writer.fuse();
+        tokio::pin!(writer);
+        let registration = ctx.handlers.connections().register(
+            session.auth.user.clone(),
+            origin,
+            session.open.keys().copied(),
+            outbound.stats().clone(),
+        );
+        Span::current().record("user", session.auth.user.as_str());
         let mut processor = State {
-            authorizer,
-            user_db,
-            doc_manager,
-            open: HashMap::new(),
-            socket,
-            presence_tx,
-            presence_rx,
-            auth: entry,
-            next_frame_id: 2,
+            frames,
+            outbound,
+            handlers: ctx.handlers,
+            user_db: ctx.user_db,
+            doc_manager: ctx.doc_manager,
+            sessions: ctx.sessions,
+            shutdown: ctx.shutdown,
+            session,
+            resume_token: None,
+            takeover_rx: None,
+            registration,
+            peer: origin.peer,
         };
-        processor.run().await;
-        Ok(())
+        let res = processor.run(writer.as_mut()).await;
+
+        let State {
+            outbound,
+            session,
+            sessions,
+            resume_token,
+            ..
+        } = processor;
+        // Flush what is still queued, such as the shutdown frame, then close the socket. A
+        // connection that failed, overflowed or was taken over is closed without waiting for it,
+        // so a takeover isn't held up by the old socket.
+        drop(outbound);
+        let flush = matches!(res, Ok(Exit::Closed | Exit::Shutdown | Exit::Kicked));
+        if flush && !writer.is_terminated() {
+            match tokio::time::timeout(ctx.outbound.write_timeout, writer).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("Failed to flush connection: {}", err),
+                Err(_) => debug!("Timed out flushing connection"),
+            }
+        }
+
+        match (res, resume_token) {
+            (Ok(Exit::TakenOver(reply_tx)), _) => {
+                debug!("Session taken over by a new connection");
+                let _ = reply_tx.send(session);
+                Ok(())
+            }
+            (Ok(Exit::Shutdown | Exit::Kicked), Some(token)) => {
+                sessions.remove(&token);
+                Ok(())
+            }
+            (res, Some(token)) => {
+                sessions.park(token, session);
+                res.map(|_| ())
+            }
+            (res, None) => res.map(|_| ()),
+        }
     }
 
-    async fn run(&mut self) -> eyre::Result<()> {
+    async fn run<W>(&mut self, mut writer: Pin<&mut W>) -> eyre::Result<Exit>
+    where
+        W: FusedFuture<Output = std::io::Result<()>>,
+    {
+        self.send(FrameType::ServerInfo {
+            info: self.handlers.server_info(),
+        });
+        self.send_resume_token();
         loop {
+            if self.outbound.is_overflowed() {
+                return Err(eyre!("outbound queue overflowed"));
+            }
             select! {
-                frame = self.socket.next() => {
+                _ = self.shutdown.triggered() => {
+                    trace!("Sending shutdown frame");
+                    let reconnect_after_seconds = self.shutdown.reconnect_after().as_secs();
+                    self.send(FrameType::Shutdown { reconnect_after_seconds });
+                    return Ok(Exit::Shutdown);
+                }
+
+                reply_tx = recv_takeover(&mut self.takeover_rx) => {
+                    return Ok(Exit::TakenOver(reply_tx));
+                }
+
+                kick = self.registration.kicked() => match kick {
+                    Kick::Connection => {
+                        info!("Kicked by an admin");
+                        self.send(FrameType::Kicked { doc: None });
+                        return Ok(Exit::Kicked);
+                    }
+                    Kick::Doc(doc) => {
+                        if self.session.open.remove(&doc).is_some() {
+                            info!("Kicked from doc {} by an admin", doc);
+                            self.registration.closed(doc);
+                            self.send(FrameType::Kicked { doc: Some(doc) });
+                        }
+                    }
+                },
+
+                res = &mut writer => {
+                    res?;
+                    return Ok(Exit::Closed);
+                }
+
+                frame = self.frames.next() => {
                     let Some(frame) = frame else {
-                        return Ok(())
+                        return Ok(Exit::Closed)
                     };
                     let frame = frame?;
                     let frame_id = frame.id;
+                    let name = frame.frame.name();
                     trace!("got frame: {:?}", frame);
+                    self.handlers.metrics().frame_received(name);
+                    let started = Instant::now();
                     if let Err(err) = self.process_frame(frame).await {
-                        info!("Error processing frame: {}", err);
-                        let message = err.to_string();
-                        let _ = self.send_error(frame_id, message).await;
-                        self.next_frame_id += 1;
+                        match &err {
+                            error::Error::Internal(report) => {
+                                warn!("Error processing frame: {:#}", report)
+                            }
+                            _ => info!("Error processing frame: {}", err),
+                        }
+                        self.send_error(frame_id, &err);
                     }
+                    self.handlers.metrics().frame_processed(name, started.elapsed());
                 }
 
-                Some(mut frame) = self.presence_rx.recv() => {
-                    while let Ok(next_frame) = self.presence_rx.try_recv() {
+                Some(mut frame) = self.session.presence_rx.recv() => {
+                    while let Ok(next_frame) = self.session.presence_rx.try_recv() {
                         frame.extend(next_frame);
                     }
+                    // A doc worker may send presence before it notices the doc was closed
+                    frame.retain(|update| self.session.open.contains_key(&update.doc));
+                    if frame.is_empty() {
+                        continue;
+                    }
                     let frame = Frame::new(
-                        self.next_frame_id,
+                        self.session.next_frame_id,
                         FrameType::Presence { updates: frame },
                     );
-                    self.socket.send(frame).await?;
-                    self.next_frame_id += 1;
+                    if self.outbound.push_presence(frame) {
+                        self.handlers.metrics().frame_sent("presence");
+                        self.session.next_frame_id += 1;
+                    } else if !self.outbound.is_overflowed() {
+                        self.handlers.metrics().presence_dropped(PresenceDrop::Outbound);
+                    }
                 }
             }
         }
     }
 
-    async fn process_frame(&mut self, frame: Frame) -> eyre::Result<()> {
+    /// Registers the session so it can be resumed if the connection drops, and tells the client
+    /// how.
+    fn send_resume_token(&mut self) {
+        if !self.sessions.is_enabled() {
+            return;
+        }
+        let (token, takeover_rx) = self.sessions.register();
+        self.resume_token = Some(token.clone());
+        self.takeover_rx = Some(takeover_rx);
+        self.send(FrameType::ResumeToken {
+            token,
+            window_seconds: self.sessions.window().as_secs(),
+        });
+    }
+
+    async fn process_frame(&mut self, frame: Frame) -> Result<(), error::Error> {
         match frame.frame {
-            FrameType::Error { error: _ } => return Err(eyre!("unexpected error frame")),
             FrameType::MintToken {
-                user: mint_user,
+                user,
                 lifetime_seconds,
-                info: mint_info,
+                info,
             } => {
-                if self.auth.user != "root" {
-                    return Err(eyre!("forbidden"));
-                }
-                info!(
-                    "Minting token for user {} with lifetime {}",
-                    mint_user, lifetime_seconds
-                );
-                let lifetime = std::time::Instant::now() + Duration::from_secs(lifetime_seconds);
-                let token = self.authorizer.mint_token(authorizer::Entry {
-                    user: mint_user,
-                    expiry: lifetime,
-                    info: mint_info,
-                });
-                self.socket
-                    .send(Frame::new_reply(
-                        self.next_frame_id,
-                        frame.id,
-                        FrameType::MintTokenResponse { token },
-                    ))
+                let token = self
+                    .handlers
+                    .mint_token(&self.session.auth, self.peer, user, info, lifetime_seconds)
                     .await?;
-                self.next_frame_id += 1;
+                self.send_reply(frame.id, FrameType::MintTokenResponse { token });
                 Ok(())
             }
-            FrameType::RevokeTokensForUser { user: revoke_user } => {
-                if self.auth.user != "root" {
-                    return Err(eyre!("forbidden"));
-                }
-                if revoke_user == "root" {
-                    return Err(eyre!("cannot use RevokeTokensForUser on root"));
-                }
-                info!("Revoking tokens for user {}", revoke_user);
-                self.authorizer.revoke_tokens_for_user(revoke_user);
-                self.send_ok(frame.id).await?;
+            FrameType::RevokeTokensForUser { user } => {
+                self.handlers
+                    .revoke_tokens_for_user(&self.session.auth, self.peer, user)
+                    .await?;
+                self.send_ok(frame.id);
+                Ok(())
+            }
+            FrameType::CreateDoc => {
+                let info = self.handlers.create_doc(&self.session.auth)?;
+                self.send_doc_info(frame.id, info);
+                Ok(())
+            }
+            FrameType::GetDoc { doc } => {
+                let info = self.handlers.get_doc(&self.session.auth, doc)?;
+                self.send_doc_info(frame.id, info);
                 Ok(())
             }
             FrameType::Open { doc } => {
@@ -220,50 +446,112 @@ where
                     .doc_manager
                     .open(
                         doc,
-                        self.auth.user.clone(),
-                        self.auth.info.clone(),
-                        self.presence_tx.clone(),
+                        self.session.auth.user.clone(),
+                        self.session.auth.info.clone(),
+                        self.session.presence_tx.clone(),
                     )
                     .await?;
-                self.open.insert(doc, handle);
-                self.send_ok(frame.id).await?;
+                self.session.open.insert(doc, handle);
+                self.registration.opened(doc);
+                self.send_ok(frame.id);
                 Ok(())
             }
             FrameType::UpdatePresence { doc, presence } => {
-                let Some(handle) = self.open.get_mut(&doc) else {
-                    return Err(eyre!("doc not open"));
+                let Some(handle) = self.session.open.get_mut(&doc) else {
+                    return Err(error::Error::DocNotOpen(doc));
                 };
                 handle.update_presence(presence).await?;
+                self.send_ok(frame.id);
                 Ok(())
             }
-            _ => {
-                info!("received unexpected frame: {:?}", frame);
+            FrameType::ListConnections => {
+                let connections = self.handlers.list_connections(&self.session.auth)?;
+                self.send_reply(frame.id, FrameType::ConnectionList { connections });
                 Ok(())
             }
+            FrameType::ListOpenDocs => {
+                let docs = self.handlers.list_open_docs(&self.session.auth)?;
+                self.send_reply(frame.id, FrameType::OpenDocList { docs });
+                Ok(())
+            }
+            FrameType::KickConnection { connection } => {
+                self.handlers
+                    .kick_connection(&self.session.auth, self.peer, connection)
+                    .await?;
+                self.send_ok(frame.id);
+                Ok(())
+            }
+            FrameType::KickFromDoc { connection, doc } => {
+                self.handlers
+                    .kick_from_doc(&self.session.auth, self.peer, connection, doc)
+                    .await?;
+                self.send_ok(frame.id);
+                Ok(())
+            }
+            FrameType::QueryAuditLog { after, limit } => {
+                let entries = self
+                    .handlers
+                    .query_audit_log(&self.session.auth, after, limit)?;
+                self.send_reply(frame.id, FrameType::AuditLog { entries });
+                Ok(())
+            }
+            FrameType::GetServerInfo => {
+                let info = self.handlers.server_info();
+                self.send_reply(frame.id, FrameType::ServerInfo { info });
+                Ok(())
+            }
+            FrameType::Authenticate { .. } | FrameType::Resume { .. } => Err(
+                error::Error::UnexpectedFrame("already authenticated".to_string()),
+            ),
+            FrameType::UnknownFrame => Err(error::Error::UnknownFrame),
+            FrameType::Malformed { error } => Err(error::Error::InvalidFrame(error)),
+            _ => Err(error::Error::UnexpectedFrame(
+                "only sent by the server".to_string(),
+            )),
         }
     }
 
-    async fn send_ok(&mut self, reply_to: i32) -> std::io::Result<()> {
-        self.socket
-            .send(Frame::new_reply(
-                self.next_frame_id,
-                reply_to,
-                FrameType::Ok,
+    async fn send_shutdown(socket: &mut S, shutdown: &Shutdown, id: i32) -> std::io::Result<()> {
+        trace!("Sending shutdown frame");
+        let reconnect_after_seconds = shutdown.reconnect_after().as_secs();
+        socket
+            .send(Frame::new(
+                id,
+                FrameType::Shutdown {
+                    reconnect_after_seconds,
+                },
             ))
             .await?;
-        self.next_frame_id += 1;
-        Ok(())
+        socket.close().await
     }
 
-    async fn send_error(&mut self, reply_to: i32, message: String) -> std::io::Result<()> {
-        self.socket
-            .send(Frame::new_reply(
-                self.next_frame_id,
-                reply_to,
-                FrameType::Error { error: message },
-            ))
-            .await?;
-        self.next_frame_id += 1;
-        Ok(())
+    /// Queues a frame with the next id.
+    fn send(&mut self, frame: FrameType) {
+        self.handlers.metrics().frame_sent(frame.name());
+        self.outbound
+            .push(Frame::new(self.session.next_frame_id, frame));
+        self.session.next_frame_id += 1;
+    }
+
+    fn send_reply(&mut self, reply_to: i32, frame: FrameType) {
+        self.handlers.metrics().frame_sent(frame.name());
+        self.outbound.push(Frame::new_reply(
+            self.session.next_frame_id,
+            reply_to,
+            frame,
+        ));
+        self.session.next_frame_id += 1;
+    }
+
+    fn send_ok(&mut self, reply_to: i32) {
+        self.send_reply(reply_to, FrameType::Ok);
+    }
+
+    fn send_doc_info(&mut self, reply_to: i32, info: DocInfo) {
+        self.send_reply(reply_to, FrameType::DocInfo { info });
+    }
+
+    fn send_error(&mut self, reply_to: i32, err: &error::Error) {
+        self.send_reply(reply_to, err.to_frame());
     }
 }
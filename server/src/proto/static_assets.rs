@@ -0,0 +1,457 @@
+use bytes::Bytes;
+use http::{header, HeaderMap, StatusCode};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tracing::{debug, trace};
+
+/// How long browsers may cache assets other than HTML pages without revalidating.
+const MAX_AGE_SECONDS: u64 = 60 * 60;
+
+/// Precompressed variants we look for, in order of preference.
+const ENCODINGS: &[(&str, &str)] = &[("br", ".br"), ("gzip", ".gz")];
+
+/// Files served over HTTP, either from a directory on disk or embedded in the binary.
+///
+/// Precompressed variants are served from `<path>.br` and `<path>.gz` if the client accepts them.
+#[derive(Clone, Debug)]
+pub struct StaticAssets(Arc<Source>);
+
+#[derive(Debug)]
+enum Source {
+    Directory(PathBuf),
+    Embedded(HashMap<String, Asset>),
+}
+
+#[derive(Debug, Clone)]
+struct Asset {
+    body: Bytes,
+    etag: String,
+}
+
+/// A file found for a request. Files on disk are only read if the response needs the body.
+enum Found {
+    Embedded(Asset),
+    File { path: PathBuf, etag: String },
+}
+
+impl Asset {
+    fn new(body: Bytes) -> Self {
+        let etag = derive_etag(&body);
+        Self { body, etag }
+    }
+}
+
+impl StaticAssets {
+    pub fn directory(root: impl Into<PathBuf>) -> Self {
+        Self(Arc::new(Source::Directory(root.into())))
+    }
+
+    /// Files are given as paths relative to the root, such as `core.wasm` or `js/app.js`.
+    pub fn embedded<I>(files: I) -> Self
+    where
+        I: IntoIterator<Item = (&'static str, &'static [u8])>,
+    {
+        let files = files
+            .into_iter()
+            .map(|(path, body)| (path.to_string(), Asset::new(Bytes::from_static(body))))
+            .collect();
+        Self(Arc::new(Source::Embedded(files)))
+    }
+
+    /// Builds the response to a GET or HEAD request, or returns `None` if there is no such file.
+    pub async fn respond<B>(&self, req: &http::Request<B>) -> Option<http::Response<Bytes>> {
+        let path = asset_path(req.uri().path())?;
+        let headers = req.headers();
+
+        let mut candidates = Vec::new();
+        for &(encoding, suffix) in ENCODINGS {
+            if accepts_encoding(headers, encoding) {
+                candidates.push((Some(encoding), format!("{}{}", path, suffix)));
+            }
+        }
+        candidates.push((None, path.clone()));
+
+        for (encoding, candidate) in candidates {
+            let Some(found) = self.find(&candidate).await else {
+                continue;
+            };
+            let etag = match &found {
+                Found::Embedded(asset) => &asset.etag,
+                Found::File { etag, .. } => etag,
+            };
+            let not_modified = etag_matches(headers, etag);
+
+            let mut response = http::Response::builder()
+                .header(header::CONTENT_TYPE, content_type(&path))
+                .header(header::ETAG, etag.as_str())
+                .header(header::CACHE_CONTROL, cache_control(&path))
+                .header(header::VARY, "Accept-Encoding");
+            if let Some(encoding) = encoding {
+                response = response.header(header::CONTENT_ENCODING, encoding);
+            }
+            let response = if not_modified {
+                response.status(StatusCode::NOT_MODIFIED).body(Bytes::new())
+            } else {
+                let body = match found {
+                    Found::Embedded(asset) => asset.body,
+                    Found::File { path, .. } => match tokio::fs::read(&path).await {
+                        Ok(body) => body.into(),
+                        Err(err) => {
+                            debug!("failed to read {}: {}", path.display(), err);
+                            continue;
+                        }
+                    },
+                };
+                response.status(StatusCode::OK).body(body)
+            };
+            trace!("serving {} for {}", candidate, req.uri().path());
+            return Some(response.unwrap());
+        }
+        None
+    }
+
+    async fn find(&self, path: &str) -> Option<Found> {
+        match self.0.as_ref() {
+            Source::Embedded(files) => files.get(path).cloned().map(Found::Embedded),
+            Source::Directory(root) => {
+                let path = root.join(path);
+                let metadata = tokio::fs::metadata(&path).await.ok()?;
+                if !metadata.is_file() {
+                    return None;
+                }
+                // Derived from the metadata so that revalidating doesn't read the whole file
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified.as_nanos());
+                Some(Found::File { path, etag })
+            }
+        }
+    }
+}
+
+/// Maps a request path to a relative file path, rejecting anything that could escape the root.
+fn asset_path(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment if segment.contains(['\\', '\0']) => return None,
+            segment => segments.push(segment),
+        }
+    }
+    let mut path = segments.join("/");
+    if decoded.ends_with('/') || path.is_empty() {
+        if !path.is_empty() {
+            path.push('/');
+        }
+        path.push_str("index.html");
+    }
+    Some(path)
+}
+
+pub(crate) fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            // Checked first, as from_str_radix would also accept a sign such as `%+1`
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, ext)| ext);
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "wasm" => "application/wasm",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// HTML pages are always revalidated so that a deploy is picked up immediately, other assets can
+/// be cached for a while.
+fn cache_control(path: &str) -> String {
+    if content_type(path).starts_with("text/html") {
+        "no-cache".to_string()
+    } else {
+        format!("public, max-age={}", MAX_AGE_SECONDS)
+    }
+}
+
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            if !parts
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(encoding))
+            {
+                return false;
+            }
+            // Reject explicitly unacceptable encodings such as `br;q=0`
+            parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .all(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+        })
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+fn derive_etag(body: &[u8]) -> String {
+    use sha1::{Digest, Sha1};
+    use std::fmt::Write;
+    let hash = Sha1::digest(body);
+    let mut etag = String::with_capacity(34);
+    etag.push('"');
+    for byte in &hash[..16] {
+        let _ = write!(etag, "{:02x}", byte);
+    }
+    etag.push('"');
+    etag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, headers: &[(header::HeaderName, &str)]) -> http::Request<()> {
+        let mut req = http::Request::builder().uri(path);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    fn assets() -> StaticAssets {
+        StaticAssets::embedded([
+            ("index.html", &b"<p>home</p>"[..]),
+            ("docs/index.html", b"<p>docs</p>"),
+            ("js/app.js", b"app()"),
+            ("js/app.js.gz", b"gzipped app()"),
+        ])
+    }
+
+    #[test]
+    fn maps_paths_to_files() {
+        assert_eq!(asset_path("/js/app.js").as_deref(), Some("js/app.js"));
+        assert_eq!(asset_path("/js//./app.js").as_deref(), Some("js/app.js"));
+        assert_eq!(asset_path("/a%20b.txt").as_deref(), Some("a b.txt"));
+    }
+
+    #[test]
+    fn maps_directories_to_index_html() {
+        assert_eq!(asset_path("/").as_deref(), Some("index.html"));
+        assert_eq!(asset_path("").as_deref(), Some("index.html"));
+        assert_eq!(asset_path("/docs/").as_deref(), Some("docs/index.html"));
+        assert_eq!(asset_path("/docs").as_deref(), Some("docs"));
+    }
+
+    #[test]
+    fn rejects_parent_segments() {
+        assert_eq!(asset_path("/../etc/passwd"), None);
+        assert_eq!(asset_path("/js/../../etc/passwd"), None);
+        assert_eq!(asset_path("/%2e%2e/etc/passwd"), None);
+        assert_eq!(asset_path("/%2E%2E/etc/passwd"), None);
+        assert_eq!(asset_path("/js%2F..%2F..%2Fetc/passwd"), None);
+        assert_eq!(asset_path("/.%2e/etc/passwd"), None);
+    }
+
+    #[test]
+    fn rejects_backslashes_and_nul() {
+        assert_eq!(asset_path("/..\\etc\\passwd"), None);
+        assert_eq!(asset_path("/js%5C..%5Capp.js"), None);
+        assert_eq!(asset_path("/app.js%00.html"), None);
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        assert_eq!(percent_decode("/%"), None);
+        assert_eq!(percent_decode("/%2"), None);
+        assert_eq!(percent_decode("/%zz"), None);
+        assert_eq!(percent_decode("/%+1"), None);
+        assert_eq!(percent_decode("/%ff"), None);
+        assert_eq!(asset_path("/%2"), None);
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("/a%2Fb%20c").as_deref(), Some("/a/b c"));
+        assert_eq!(percent_decode("/%C3%A9").as_deref(), Some("/é"));
+    }
+
+    #[test]
+    fn parses_accept_encoding() {
+        let accept = |value| headers(header::ACCEPT_ENCODING, value);
+        assert!(accepts_encoding(&accept("gzip"), "gzip"));
+        assert!(accepts_encoding(&accept("br, GZIP;q=0.5"), "gzip"));
+        assert!(!accepts_encoding(&accept("gzip;q=0"), "gzip"));
+        assert!(!accepts_encoding(&accept("gzip;q=nope"), "gzip"));
+        assert!(!accepts_encoding(&accept("gzipped, x-gzip"), "gzip"));
+        assert!(!accepts_encoding(&HeaderMap::new(), "gzip"));
+    }
+
+    #[test]
+    fn matches_etags() {
+        let etag = "\"abc\"";
+        let if_none_match = |value| headers(header::IF_NONE_MATCH, value);
+        assert!(etag_matches(&if_none_match("\"abc\""), etag));
+        assert!(etag_matches(&if_none_match("W/\"abc\""), etag));
+        assert!(etag_matches(&if_none_match("\"xyz\", \"abc\""), etag));
+        assert!(etag_matches(&if_none_match("*"), etag));
+        assert!(!etag_matches(&if_none_match("\"abcd\""), etag));
+        assert!(!etag_matches(&if_none_match("abc"), etag));
+        assert!(!etag_matches(&HeaderMap::new(), etag));
+    }
+
+    #[tokio::test]
+    async fn serves_index_html_for_directories() {
+        let response = assets().respond(&request("/docs/", &[])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"<p>docs</p>");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+    }
+
+    #[tokio::test]
+    async fn serves_nothing_outside_the_root() {
+        let assets = assets();
+        assert!(assets
+            .respond(&request("/../index.html", &[]))
+            .await
+            .is_none());
+        assert!(assets.respond(&request("/missing.js", &[])).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_precompressed_variants() {
+        let assets = assets();
+        let gzip = [(header::ACCEPT_ENCODING, "br, gzip")];
+        let response = assets.respond(&request("/js/app.js", &gzip)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(response.body().as_ref(), b"gzipped app()");
+
+        let response = assets.respond(&request("/js/app.js", &[])).await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(response.body().as_ref(), b"app()");
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_etags() {
+        let assets = assets();
+        let response = assets.respond(&request("/js/app.js", &[])).await.unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let if_none_match = [(header::IF_NONE_MATCH, etag.as_str())];
+        let response = assets
+            .respond(&request("/js/app.js", &if_none_match))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+
+        // The gzipped variant is a different body, so it has a different ETag
+        let gzip = [
+            (header::IF_NONE_MATCH, etag.as_str()),
+            (header::ACCEPT_ENCODING, "gzip"),
+        ];
+        let response = assets.respond(&request("/js/app.js", &gzip)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serves_files_from_a_directory() {
+        let root = std::env::temp_dir().join(format!("shrub-static-assets-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/index.html"), "<p>docs</p>").unwrap();
+        std::fs::write(root.with_extension("secret"), "secret").unwrap();
+        let assets = StaticAssets::directory(&root);
+
+        let response = assets.respond(&request("/docs/", &[])).await.unwrap();
+        assert_eq!(response.body().as_ref(), b"<p>docs</p>");
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let if_none_match = [(header::IF_NONE_MATCH, etag.as_str())];
+        let response = assets
+            .respond(&request("/docs/index.html", &if_none_match))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // A directory itself isn't served
+        assert!(assets.respond(&request("/docs", &[])).await.is_none());
+        let escape = format!(
+            "/%2e%2e/{}",
+            root.with_extension("secret")
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+        );
+        assert!(assets.respond(&request(&escape, &[])).await.is_none());
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(root.with_extension("secret")).unwrap();
+    }
+}
@@ -0,0 +1,648 @@
+//! The permessage-deflate WebSocket extension ([RFC 7692]).
+//!
+//! tungstenite doesn't support extensions, so when compression is negotiated the connection is
+//! driven by [`DeflateStream`] instead of a `WebSocketStream`.
+//!
+//! [RFC 7692]: https://datatracker.ietf.org/doc/html/rfc7692
+
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures::{Sink, Stream};
+use pin_project::pin_project;
+use std::borrow::Cow;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Control, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::{CloseFrame, Frame, FrameHeader};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+const MAX_MESSAGE_LEN: usize = 1024 * 1024 * 16;
+
+/// Control frames can't be fragmented and carry at most this much (RFC 6455 §5.5).
+const MAX_CONTROL_LEN: usize = 125;
+
+/// Messages shorter than this aren't worth compressing.
+const MIN_COMPRESS_LEN: usize = 64;
+
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: bool,
+}
+
+impl DeflateParams {
+    /// Picks the first permessage-deflate offer from the `Sec-WebSocket-Extensions` headers of a
+    /// request that we can accept.
+    pub fn negotiate<'a>(headers: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        for header in headers {
+            for offer in header.split(',') {
+                if let Some(params) = Self::parse_offer(offer) {
+                    return Some(params);
+                }
+            }
+        }
+        None
+    }
+
+    fn parse_offer(offer: &str) -> Option<Self> {
+        let mut parts = offer.split(';').map(str::trim);
+        if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") {
+            return None;
+        }
+        let mut params = Self::default();
+        for param in parts {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                // We can always inflate with the full window, whatever the client uses
+                ("client_max_window_bits", _) => {}
+                // Our compressor only supports the default window size
+                ("server_max_window_bits", Some("15")) => params.server_max_window_bits = true,
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+
+    /// The value of the `Sec-WebSocket-Extensions` header to send in the upgrade response.
+    pub fn response_header(&self) -> String {
+        let mut value = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits {
+            value.push_str("; server_max_window_bits=15");
+        }
+        value
+    }
+}
+
+/// A server-side WebSocket connection with permessage-deflate applied.
+///
+/// Pings are answered and the close handshake is echoed, mirroring what `WebSocketStream` does.
+#[pin_project]
+pub struct DeflateStream<S> {
+    #[pin]
+    inner: Framed<S, DeflateCodec>,
+    pending_pong: Option<Vec<u8>>,
+    closed: bool,
+}
+
+impl<S> DeflateStream<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub fn new(socket: S, params: DeflateParams) -> Self {
+        Self {
+            inner: Framed::new(socket, DeflateCodec::new(params)),
+            pending_pong: None,
+            closed: false,
+        }
+    }
+}
+
+impl<S> DeflateStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Sends `msg` if the sink is ready without waiting, as control replies are best-effort.
+    fn try_send_control(
+        mut inner: Pin<&mut Framed<S, DeflateCodec>>,
+        cx: &mut Context<'_>,
+        msg: Message,
+    ) -> Result<bool, std::io::Error> {
+        match inner.as_mut().poll_ready(cx)? {
+            Poll::Ready(()) => {
+                inner.as_mut().start_send(msg)?;
+                let _ = inner.poll_flush(cx)?;
+                Ok(true)
+            }
+            Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl<S> Stream for DeflateStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = std::io::Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if *this.closed {
+                return Poll::Ready(None);
+            }
+            if let Some(pong) = this.pending_pong.take() {
+                if !Self::try_send_control(this.inner.as_mut(), cx, Message::Pong(pong.clone()))? {
+                    *this.pending_pong = Some(pong);
+                }
+            }
+            match ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(Ok(Message::Ping(data))) => *this.pending_pong = Some(data),
+                Some(Ok(Message::Close(frame))) => {
+                    *this.closed = true;
+                    let _ = Self::try_send_control(this.inner.as_mut(), cx, Message::Close(frame));
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+impl<S> Sink<Message> for DeflateStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+pub struct DeflateCodec {
+    params: DeflateParams,
+    compress: Compress,
+    decompress: Decompress,
+    header: Option<(FrameHeader, usize)>,
+    partial: Option<PartialMessage>,
+}
+
+struct PartialMessage {
+    text: bool,
+    compressed: bool,
+    data: Vec<u8>,
+}
+
+impl DeflateCodec {
+    fn new(params: DeflateParams) -> Self {
+        Self {
+            params,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            header: None,
+            partial: None,
+        }
+    }
+
+    fn deflate(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(invalid_data)?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed >= data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        // The sync flush trailer is implied by the extension and must be stripped
+        if out.ends_with(&DEFLATE_TRAILER) {
+            out.truncate(out.len() - DEFLATE_TRAILER.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    fn inflate(&mut self, mut data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        data.extend_from_slice(&DEFLATE_TRAILER);
+        let start = self.decompress.total_in();
+        let mut out = Vec::with_capacity(data.len() * 4);
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            self.decompress
+                .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(invalid_data)?;
+            if out.len() > MAX_MESSAGE_LEN {
+                return Err(invalid_data("message too long"));
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed >= data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if self.params.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+
+    fn finish_message(&mut self) -> std::io::Result<Message> {
+        let partial = self
+            .partial
+            .take()
+            .expect("finish_message without a message");
+        let data = if partial.compressed {
+            self.inflate(partial.data)?
+        } else {
+            partial.data
+        };
+        if partial.text {
+            String::from_utf8(data)
+                .map(Message::Text)
+                .map_err(invalid_data)
+        } else {
+            Ok(Message::Binary(data))
+        }
+    }
+}
+
+impl Decoder for DeflateCodec {
+    type Item = Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            if self.header.is_none() {
+                let mut cursor = Cursor::new(&src[..]);
+                let Some((header, len)) = FrameHeader::parse(&mut cursor).map_err(invalid_data)?
+                else {
+                    return Ok(None);
+                };
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|&len| len <= MAX_MESSAGE_LEN)
+                    .ok_or_else(|| invalid_data("frame too long"))?;
+                // Checked before buffering the payload, so a bad frame is rejected right away
+                if header.mask.is_none() {
+                    return Err(invalid_data("unmasked client frame"));
+                }
+                if let OpCode::Control(_) = header.opcode {
+                    if !header.is_final || len > MAX_CONTROL_LEN {
+                        return Err(invalid_data("invalid control frame"));
+                    }
+                }
+                src.advance(cursor.position() as usize);
+                self.header = Some((header, len));
+            }
+
+            let (_, len) = self.header.as_ref().unwrap();
+            if src.len() < *len {
+                src.reserve(*len - src.len());
+                return Ok(None);
+            }
+            let (header, len) = self.header.take().unwrap();
+            let mut payload = src.split_to(len).to_vec();
+            if let Some(mask) = header.mask {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+            if header.rsv2 || header.rsv3 {
+                return Err(invalid_data("unsupported reserved bits"));
+            }
+
+            match header.opcode {
+                OpCode::Control(control) => {
+                    if header.rsv1 {
+                        return Err(invalid_data("invalid control frame"));
+                    }
+                    return match control {
+                        Control::Close => Ok(Some(Message::Close(parse_close(payload)?))),
+                        Control::Ping => Ok(Some(Message::Ping(payload))),
+                        Control::Pong => Ok(Some(Message::Pong(payload))),
+                        Control::Reserved(_) => Err(invalid_data("unknown control frame")),
+                    };
+                }
+                OpCode::Data(Data::Continue) => {
+                    let Some(partial) = self.partial.as_mut() else {
+                        return Err(invalid_data("unexpected continuation frame"));
+                    };
+                    // Only the first frame of a message says whether it's compressed
+                    if header.rsv1 {
+                        return Err(invalid_data("compressed continuation frame"));
+                    }
+                    if partial.data.len() + payload.len() > MAX_MESSAGE_LEN {
+                        return Err(invalid_data("message too long"));
+                    }
+                    partial.data.extend_from_slice(&payload);
+                }
+                OpCode::Data(data @ (Data::Text | Data::Binary)) => {
+                    if self.partial.is_some() {
+                        return Err(invalid_data("expected continuation frame"));
+                    }
+                    self.partial = Some(PartialMessage {
+                        text: data == Data::Text,
+                        compressed: header.rsv1,
+                        data: payload,
+                    });
+                }
+                OpCode::Data(Data::Reserved(_)) => {
+                    return Err(invalid_data("unknown data frame"));
+                }
+            }
+
+            if header.is_final {
+                return self.finish_message().map(Some);
+            }
+        }
+    }
+}
+
+impl Encoder<Message> for DeflateCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let frame = match item {
+            Message::Text(text) => self.data_frame(text.into_bytes(), Data::Text)?,
+            Message::Binary(data) => self.data_frame(data, Data::Binary)?,
+            Message::Ping(data) => Frame::ping(data),
+            Message::Pong(data) => Frame::pong(data),
+            Message::Close(frame) => Frame::close(frame),
+            Message::Frame(frame) => frame,
+        };
+        frame.format(&mut dst.writer()).map_err(invalid_data)
+    }
+}
+
+impl DeflateCodec {
+    fn data_frame(&mut self, data: Vec<u8>, opcode: Data) -> std::io::Result<Frame> {
+        if data.len() < MIN_COMPRESS_LEN {
+            return Ok(Frame::message(data, OpCode::Data(opcode), true));
+        }
+        let compressed = self.deflate(&data)?;
+        let mut frame = Frame::message(compressed, OpCode::Data(opcode), true);
+        frame.header_mut().rsv1 = true;
+        Ok(frame)
+    }
+}
+
+fn parse_close(payload: Vec<u8>) -> std::io::Result<Option<CloseFrame<'static>>> {
+    if payload.len() < 2 {
+        return Ok(None);
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    let reason = String::from_utf8(payload[2..].to_vec()).map_err(invalid_data)?;
+    Ok(Some(CloseFrame {
+        code: CloseCode::from(code),
+        reason: Cow::Owned(reason),
+    }))
+}
+
+fn invalid_data<E>(err: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// A frame as a client sends it, masked.
+    fn client_frame(payload: Vec<u8>, opcode: OpCode, is_final: bool, rsv1: bool) -> BytesMut {
+        let header = FrameHeader {
+            is_final,
+            rsv1,
+            opcode,
+            mask: Some(MASK),
+            ..FrameHeader::default()
+        };
+        let mut writer = BytesMut::new().writer();
+        Frame::from_payload(header, payload)
+            .format(&mut writer)
+            .unwrap();
+        writer.into_inner()
+    }
+
+    fn text_frame(payload: Vec<u8>, rsv1: bool) -> BytesMut {
+        client_frame(payload, OpCode::Data(Data::Text), true, rsv1)
+    }
+
+    /// Splits a frame the server sent into its header and payload.
+    fn server_frame(mut buf: BytesMut) -> (FrameHeader, Vec<u8>) {
+        let mut cursor = Cursor::new(&buf[..]);
+        let (header, len) = FrameHeader::parse(&mut cursor).unwrap().unwrap();
+        buf.advance(cursor.position() as usize);
+        assert_eq!(buf.len() as u64, len);
+        (header, buf.to_vec())
+    }
+
+    fn message(len: usize) -> String {
+        "shrubbery ".repeat(len / 10 + 1)[..len].to_string()
+    }
+
+    #[test]
+    fn round_trips_compressed_messages() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut client = DeflateCodec::new(DeflateParams::default());
+        let text = message(1000);
+
+        let mut buf = text_frame(client.deflate(text.as_bytes()).unwrap(), true);
+        let decoded = server.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some(Message::Text(text.clone())));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::new();
+        server
+            .encode(Message::Text(text.clone()), &mut buf)
+            .unwrap();
+        let (header, payload) = server_frame(buf);
+        assert!(header.rsv1);
+        assert!(header.mask.is_none());
+        assert!(payload.len() < text.len());
+        assert_eq!(client.inflate(payload).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn sends_short_messages_uncompressed() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut buf = BytesMut::new();
+        server.encode(Message::Text("hi".into()), &mut buf).unwrap();
+        let (header, payload) = server_frame(buf);
+        assert!(!header.rsv1);
+        assert_eq!(payload, b"hi");
+
+        let mut buf = text_frame(b"hi".to_vec(), false);
+        let decoded = server.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some(Message::Text("hi".into())));
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut buf = text_frame(b"hello".to_vec(), false);
+        let rest = buf.split_off(4);
+        assert_eq!(server.decode(&mut buf).unwrap(), None);
+        buf.unsplit(rest);
+        let decoded = server.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some(Message::Text("hello".into())));
+    }
+
+    #[test]
+    fn keeps_the_context_between_messages() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut client = DeflateCodec::new(DeflateParams::default());
+        let text = message(500);
+
+        let first = client.deflate(text.as_bytes()).unwrap();
+        let second = client.deflate(text.as_bytes()).unwrap();
+        // The second copy refers back to the first
+        assert!(second.len() < first.len());
+        for payload in [first, second] {
+            let decoded = server.decode(&mut text_frame(payload, true)).unwrap();
+            assert_eq!(decoded, Some(Message::Text(text.clone())));
+        }
+    }
+
+    #[test]
+    fn resets_the_context_without_takeover() {
+        let params = DeflateParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            server_max_window_bits: false,
+        };
+        let mut server = DeflateCodec::new(params.clone());
+        let mut client = DeflateCodec::new(params);
+        let text = message(500);
+
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let mut buf = BytesMut::new();
+            server
+                .encode(Message::Text(text.clone()), &mut buf)
+                .unwrap();
+            sent.push(server_frame(buf).1);
+        }
+        assert_eq!(sent[0], sent[1]);
+        // Both inflate on their own, as the client resets its context too
+        for payload in sent {
+            assert_eq!(client.inflate(payload).unwrap(), text.as_bytes());
+        }
+
+        for _ in 0..2 {
+            let payload = client.deflate(text.as_bytes()).unwrap();
+            client.compress.reset();
+            let decoded = server.decode(&mut text_frame(payload, true)).unwrap();
+            assert_eq!(decoded, Some(Message::Text(text.clone())));
+        }
+    }
+
+    #[test]
+    fn reassembles_fragmented_messages() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut client = DeflateCodec::new(DeflateParams::default());
+        let text = message(1000);
+        let mut compressed = client.deflate(text.as_bytes()).unwrap();
+        let tail = compressed.split_off(compressed.len() / 2);
+
+        let mut buf = client_frame(compressed, OpCode::Data(Data::Text), false, true);
+        // Control frames may come between the fragments
+        buf.extend_from_slice(&client_frame(
+            b"ping".to_vec(),
+            OpCode::Control(Control::Ping),
+            true,
+            false,
+        ));
+        buf.extend_from_slice(&client_frame(
+            tail,
+            OpCode::Data(Data::Continue),
+            true,
+            false,
+        ));
+
+        let ping = server.decode(&mut buf).unwrap();
+        assert_eq!(ping, Some(Message::Ping(b"ping".to_vec())));
+        let decoded = server.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some(Message::Text(text)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut writer = BytesMut::new().writer();
+        Frame::message(b"hi".to_vec(), OpCode::Data(Data::Text), true)
+            .format(&mut writer)
+            .unwrap();
+        assert!(server.decode(&mut writer.into_inner()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_control_frames() {
+        let ping = OpCode::Control(Control::Ping);
+        let oversized = client_frame(vec![0; MAX_CONTROL_LEN + 1], ping, true, false);
+        let fragmented = client_frame(b"ping".to_vec(), ping, false, false);
+        let compressed = client_frame(b"ping".to_vec(), ping, true, true);
+        for mut buf in [oversized, fragmented, compressed] {
+            let mut server = DeflateCodec::new(DeflateParams::default());
+            assert!(server.decode(&mut buf).is_err());
+        }
+
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut buf = client_frame(vec![0; MAX_CONTROL_LEN], ping, true, false);
+        let decoded = server.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some(Message::Ping(vec![0; MAX_CONTROL_LEN])));
+    }
+
+    #[test]
+    fn rejects_misordered_fragments() {
+        let text = OpCode::Data(Data::Text);
+        let continuation = OpCode::Data(Data::Continue);
+
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut buf = client_frame(b"hi".to_vec(), continuation, true, false);
+        assert!(server.decode(&mut buf).is_err());
+
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut buf = client_frame(b"hi".to_vec(), text, false, false);
+        buf.extend_from_slice(&client_frame(b"hi".to_vec(), text, true, false));
+        assert!(server.decode(&mut buf).is_err());
+
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut buf = client_frame(b"hi".to_vec(), text, false, false);
+        buf.extend_from_slice(&client_frame(b"hi".to_vec(), continuation, true, true));
+        assert!(server.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn rejects_messages_inflating_past_the_limit() {
+        let mut server = DeflateCodec::new(DeflateParams::default());
+        let mut client = DeflateCodec::new(DeflateParams::default());
+        let compressed = client.deflate(&vec![0; MAX_MESSAGE_LEN + 1]).unwrap();
+        assert!(compressed.len() < MAX_MESSAGE_LEN / 100);
+
+        let mut buf = client_frame(compressed, OpCode::Data(Data::Binary), true, true);
+        assert!(server.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::doc_manager::DocHandle;
+use crate::shutdown::Shutdown;
+use crate::state::authorizer;
+use shrubbery_common::frame::PresenceFrame;
+use shrubbery_common::DocId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, trace};
+
+/// How long a resume waits for the connection still holding the session to hand it over.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of a connection that outlives the socket, so a client that reconnects within the
+/// resume window keeps its open docs, client ids and any presence sent to it in the meantime.
+pub struct Session {
+    pub auth: authorizer::Entry,
+    pub open: HashMap<DocId, DocHandle>,
+    pub presence_tx: mpsc::Sender<Vec<PresenceFrame>>,
+    pub presence_rx: mpsc::Receiver<Vec<PresenceFrame>>,
+    pub next_frame_id: i32,
+}
+
+impl Session {
+    pub fn new(auth: authorizer::Entry, next_frame_id: i32) -> Self {
+        let (presence_tx, presence_rx) = mpsc::channel(12);
+        Self {
+            auth,
+            open: HashMap::new(),
+            presence_tx,
+            presence_rx,
+            next_frame_id,
+        }
+    }
+}
+
+/// Asks the connection holding a session to give it up, replying with the session.
+pub type TakeoverRequest = oneshot::Sender<Session>;
+
+/// Sessions by resume token. A session is either held by a live connection or parked after its
+/// socket dropped, until it is resumed or the resume window passes.
+#[derive(Clone, Debug)]
+pub struct SessionStore {
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+    window: Duration,
+    shutdown: Shutdown,
+}
+
+enum Slot {
+    Active(oneshot::Sender<TakeoverRequest>),
+    Parked(Session, Instant),
+}
+
+impl SessionStore {
+    /// A `window` of zero disables resumption.
+    pub fn new(window: Duration, shutdown: Shutdown) -> Self {
+        Self {
+            slots: Default::default(),
+            window,
+            shutdown,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Registers a session held by a live connection, returning its resume token and the
+    /// receiver through which another connection may ask to take it over.
+    pub fn register(&self) -> (String, oneshot::Receiver<TakeoverRequest>) {
+        let token = random_token();
+        let (tx, rx) = oneshot::channel();
+        self.slots
+            .lock()
+            .unwrap()
+            .insert(token.clone(), Slot::Active(tx));
+        (token, rx)
+    }
+
+    /// Keeps the session of a dropped connection until the resume window passes.
+    pub fn park(&self, token: String, session: Session) {
+        let expiry = Instant::now() + self.window;
+        trace!("parking session for {}", session.auth.user);
+        self.slots
+            .lock()
+            .unwrap()
+            .insert(token.clone(), Slot::Parked(session, expiry));
+
+        let store = self.clone();
+        let shutdown = self.shutdown.clone();
+        self.shutdown.spawn(async move {
+            select! {
+                _ = tokio::time::sleep(store.window) => {}
+                _ = shutdown.triggered() => {}
+            }
+            store.expire(&token);
+        });
+    }
+
+    /// Forgets a session, such as when the server is shutting down.
+    pub fn remove(&self, token: &str) {
+        self.slots.lock().unwrap().remove(token);
+    }
+
+    /// Takes the session for `token`. If a connection still holds it, as happens when the
+    /// client notices a dropped connection before the server does, that connection is asked to
+    /// hand it over and is closed.
+    pub async fn resume(&self, token: &str) -> Option<Session> {
+        let slot = self.slots.lock().unwrap().remove(token)?;
+        let session = match slot {
+            Slot::Parked(session, expiry) if expiry > Instant::now() => session,
+            Slot::Parked(_, _) => return None,
+            Slot::Active(takeover_tx) => {
+                debug!("Taking over session from a live connection");
+                let (reply_tx, reply_rx) = oneshot::channel();
+                takeover_tx.send(reply_tx).ok()?;
+                tokio::time::timeout(TAKEOVER_TIMEOUT, reply_rx)
+                    .await
+                    .ok()?
+                    .ok()?
+            }
+        };
+        if session.auth.expiry < Instant::now() {
+            debug!(
+                "Not resuming session for {}: auth expired",
+                session.auth.user
+            );
+            return None;
+        }
+        Some(session)
+    }
+
+    fn expire(&self, token: &str) {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(Slot::Parked(session, expiry)) = slots.get(token) {
+            if *expiry <= Instant::now() || self.shutdown.is_triggered() {
+                trace!("expiring parked session for {}", session.auth.user);
+                slots.remove(token);
+            }
+        }
+    }
+}
+
+fn random_token() -> String {
+    use rand::Rng;
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(30)
+        .map(char::from)
+        .collect();
+    format!("shrubresume1:{}", suffix)
+}
+
+impl std::fmt::Debug for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Slot::Active(_) => f.write_str("Active"),
+            Slot::Parked(_, expiry) => f.debug_tuple("Parked").field(expiry).finish(),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Coordinates a graceful shutdown between the accept loop, connections and doc workers.
+///
+/// Tasks spawned through [`Shutdown::spawn`] are tracked so that [`Shutdown::drain`] can wait for
+/// them to finish after [`Shutdown::trigger`] is called.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    token: CancellationToken,
+    announced: CancellationToken,
+    tracker: TaskTracker,
+    reconnect_after: Duration,
+}
+
+impl Shutdown {
+    pub fn new(reconnect_after: Duration) -> Self {
+        Self {
+            token: CancellationToken::new(),
+            announced: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+            reconnect_after,
+        }
+    }
+
+    /// How long clients are told to wait before reconnecting.
+    pub fn reconnect_after(&self) -> Duration {
+        self.reconnect_after
+    }
+
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(task);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Marks shutdown as coming without stopping anything, so readiness fails while connections
+    /// are still accepted. Triggering implies it.
+    pub fn announce(&self) {
+        self.announced.cancel();
+    }
+
+    pub fn is_announced(&self) -> bool {
+        self.announced.is_cancelled()
+    }
+
+    pub fn trigger(&self) {
+        self.announced.cancel();
+        self.token.cancel();
+        self.tracker.close();
+    }
+
+    /// Waits for all tracked tasks to finish. Returns false if the deadline passed first.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        tokio::time::timeout(deadline, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.tracker.len()
+    }
+}
@@ -23,6 +23,17 @@ pub struct Entry {
     pub info: Option<serde_json::Value>,
 }
 
+impl Entry {
+    /// The entry for a root session, which never practically expires.
+    pub fn root() -> Self {
+        Entry {
+            user: "root".to_string(),
+            expiry: Instant::now() + Duration::from_secs(60 * 60 * 24 * 365 * 100),
+            info: None,
+        }
+    }
+}
+
 impl Authorizer {
     pub fn new(root_token: String) -> Self {
         Self(Arc::new(Mutex::new(Inner {
@@ -48,11 +59,7 @@ impl Authorizer {
     pub fn authenticate(&self, token: &str) -> Option<Entry> {
         let inner = self.0.lock().unwrap();
         if inner.root_token == token {
-            return Some(Entry {
-                user: "root".to_string(),
-                expiry: Instant::now() + Duration::from_secs(60 * 60 * 24 * 365 * 100),
-                info: None,
-            });
+            return Some(Entry::root());
         }
         let entry = inner.by_token.get(token)?.clone();
         if entry.expiry < Instant::now() {
@@ -0,0 +1,49 @@
+use crate::state::authorizer::Entry;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+/// Maps verified TLS client certificates to users by the common name in their subject.
+///
+/// Certificates must already have been verified against the client CA during the handshake.
+/// Certificates whose common name isn't mapped don't authenticate anyone, and their connections
+/// fall back to token authentication.
+#[derive(Clone, Debug)]
+pub struct ClientCertAuthorizer(Arc<HashMap<String, CertUser>>);
+
+#[derive(Debug, Deserialize)]
+struct CertUser {
+    user: String,
+    info: Option<serde_json::Value>,
+}
+
+impl ClientCertAuthorizer {
+    /// Parses a JSON object mapping common names to `{"user": "...", "info": ...}`.
+    pub fn from_json(json: &[u8]) -> eyre::Result<Self> {
+        let users = serde_json::from_slice(json)?;
+        Ok(Self(Arc::new(users)))
+    }
+
+    /// The session for a DER encoded client certificate, which lasts until the certificate
+    /// expires.
+    pub fn authenticate(&self, der: &[u8]) -> Option<Entry> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+        let Some(mapped) = self.0.get(common_name) else {
+            debug!("No user for client certificate with CN={}", common_name);
+            return None;
+        };
+        let lifetime = cert.validity().time_to_expiration()?;
+        debug!(
+            "Client certificate CN={} authenticated as {}",
+            common_name, mapped.user
+        );
+        Some(Entry {
+            user: mapped.user.clone(),
+            expiry: Instant::now() + lifetime,
+            info: mapped.info.clone(),
+        })
+    }
+}
@@ -1 +1,2 @@
 pub mod authorizer;
+pub mod client_certs;
@@ -0,0 +1,144 @@
+use shrubbery_common::tls::{TlsAcceptor, TlsStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, info, warn};
+
+const SELF_SIGNED_IDENTITY: &[u8] = include_bytes!("../self_signed.pfx");
+
+/// Where the server's TLS identity is loaded from.
+#[derive(Clone)]
+pub enum TlsIdentity {
+    /// PEM certificate chain and private key, served with rustls. If `client_ca` is set, clients
+    /// may authenticate with a certificate signed by it.
+    Pem {
+        cert: PathBuf,
+        key: PathBuf,
+        client_ca: Option<PathBuf>,
+    },
+    /// PKCS#12 identity, served with `native-tls`.
+    Pkcs12 { path: PathBuf, password: String },
+    /// The self-signed identity embedded in the binary, for development.
+    SelfSigned,
+}
+
+impl TlsIdentity {
+    pub async fn load(&self) -> eyre::Result<TlsAcceptor> {
+        match self {
+            TlsIdentity::Pem {
+                cert,
+                key,
+                client_ca,
+            } => load_pem(cert, key, client_ca.as_deref()).await,
+            TlsIdentity::Pkcs12 { path, password } => {
+                debug!("Loading TLS identity from {}", path.display());
+                let der = tokio::fs::read(path).await?;
+                TlsAcceptor::native_from_pkcs12(&der, password)
+            }
+            TlsIdentity::SelfSigned => {
+                TlsAcceptor::native_from_pkcs12(SELF_SIGNED_IDENTITY, "password")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+async fn load_pem(cert: &Path, key: &Path, client_ca: Option<&Path>) -> eyre::Result<TlsAcceptor> {
+    debug!(
+        "Loading TLS certificate from {} and key from {}",
+        cert.display(),
+        key.display()
+    );
+    let cert = tokio::fs::read(cert).await?;
+    let key = tokio::fs::read(key).await?;
+    let client_ca = match client_ca {
+        Some(path) => {
+            debug!("Loading TLS client CA from {}", path.display());
+            Some(tokio::fs::read(path).await?)
+        }
+        None => None,
+    };
+    TlsAcceptor::rustls_from_pem(&cert, &key, client_ca.as_deref())
+}
+
+#[cfg(not(feature = "rustls"))]
+async fn load_pem(
+    _cert: &Path,
+    _key: &Path,
+    _client_ca: Option<&Path>,
+) -> eyre::Result<TlsAcceptor> {
+    Err(eyre::eyre!(
+        "PEM certificates require shrubbery-server to be built with the rustls feature"
+    ))
+}
+
+/// A TLS acceptor whose identity can be reloaded while the server is running.
+///
+/// Each handshake uses the acceptor that was current when it started, so connections that are
+/// already established are unaffected by a reload.
+#[derive(Clone)]
+pub struct ReloadableTlsAcceptor {
+    identity: Arc<TlsIdentity>,
+    current: Arc<RwLock<Arc<TlsAcceptor>>>,
+}
+
+impl ReloadableTlsAcceptor {
+    pub async fn load(identity: TlsIdentity) -> eyre::Result<Self> {
+        let acceptor = identity.load().await?;
+        Ok(Self {
+            identity: Arc::new(identity),
+            current: Arc::new(RwLock::new(Arc::new(acceptor))),
+        })
+    }
+
+    /// Loads the identity again and swaps it in if it is valid. On error the previous acceptor
+    /// stays in use.
+    pub async fn reload(&self) -> eyre::Result<()> {
+        let acceptor = self.identity.load().await?;
+        *self.current.write().unwrap() = Arc::new(acceptor);
+        Ok(())
+    }
+
+    pub async fn accept<S>(&self, socket: S) -> std::io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = self.current.read().unwrap().clone();
+        acceptor.accept(socket).await
+    }
+
+    /// Reloads the identity whenever the process receives SIGHUP, until shutdown.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&self, shutdown: &crate::shutdown::Shutdown) -> eyre::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = signal(SignalKind::hangup())
+            .map_err(|err| eyre::eyre!("Failed to listen for SIGHUP: {}", err))?;
+        let acceptor = self.clone();
+        let task_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sighup.recv() => {}
+                    _ = task_shutdown.triggered() => return,
+                }
+                info!("Received SIGHUP, reloading TLS identity");
+                match acceptor.reload().await {
+                    Ok(()) => info!("Reloaded TLS identity"),
+                    Err(err) => warn!(
+                        "Failed to reload TLS identity, keeping the old one: {}",
+                        err
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for ReloadableTlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReloadableTlsAcceptor")
+            .finish_non_exhaustive()
+    }
+}